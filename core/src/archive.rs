@@ -0,0 +1,106 @@
+//! The download archive, like the one of yt-dlp.
+//! One `bvid cid quality` per line for every video downloaded,
+//! so that syncing a favorites folder or a space again skips them
+
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::error::Error;
+use crate::helper;
+use crate::playinfo::ParsedMedia;
+use crate::quality::Selection;
+
+pub(crate) static ARCHIVE: once_cell::sync::Lazy<Archive> =
+    once_cell::sync::Lazy::new(|| Archive::new(helper::store_dir().join("archive.txt")));
+
+/// The key of the chosen stream of a page or an episode
+pub(crate) fn key(media: &ParsedMedia, selection: &Selection) -> String {
+    format!(
+        "{} {} {}",
+        media.video_data.bvid,
+        media.cid(),
+        selection.quality
+    )
+}
+
+#[derive(Debug)]
+pub(crate) struct Archive {
+    path: PathBuf,
+    // loaded on the first use
+    keys: Mutex<Option<HashSet<String>>>,
+}
+
+impl Archive {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            keys: Mutex::new(None),
+        }
+    }
+
+    fn with_keys<T>(&self, f: impl FnOnce(&mut HashSet<String>) -> T) -> T {
+        let mut keys = self.keys.lock().unwrap_or_else(|e| e.into_inner());
+        let keys = keys.get_or_insert_with(|| {
+            std::fs::read_to_string(&self.path)
+                .map(|archive| archive.lines().map(str::to_owned).collect())
+                .unwrap_or_default()
+        });
+        f(keys)
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.with_keys(|keys| keys.contains(key))
+    }
+
+    /// If any page or quality of `bvid` has been downloaded
+    pub fn contains_bvid(&self, bvid: &str) -> bool {
+        self.with_keys(|keys| keys.iter().any(|k| k.split(' ').next() == Some(bvid)))
+    }
+
+    pub fn add(&self, key: String) -> Result<(), Error> {
+        self.with_keys(|keys| {
+            if keys.contains(&key) {
+                return Ok(());
+            }
+            if let Some(dir) = self.path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            writeln!(file, "{key}")?;
+            keys.insert(key);
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archive() {
+        let path = std::env::temp_dir().join("bili_archive_test.txt");
+        let _ = std::fs::remove_file(&path);
+        let archive = Archive::new(path.clone());
+        assert!(!archive.contains("BV1Ao4y1b7fj 1 80"));
+        archive.add("BV1Ao4y1b7fj 1 80".to_owned()).unwrap();
+        archive.add("BV1Ao4y1b7fj 1 80".to_owned()).unwrap();
+        assert!(archive.contains("BV1Ao4y1b7fj 1 80"));
+        assert!(!archive.contains("BV1Ao4y1b7fj 1 116"));
+        assert!(archive.contains_bvid("BV1Ao4y1b7fj"));
+        assert!(!archive.contains_bvid("BV1Ao4y1b7f"));
+        // another process sees it after loading the file
+        let archive = Archive::new(path.clone());
+        assert!(archive.contains("BV1Ao4y1b7fj 1 80"));
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "BV1Ao4y1b7fj 1 80\n"
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
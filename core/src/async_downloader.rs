@@ -0,0 +1,660 @@
+//! AsyncDownloader
+//! Ask executor to control tasks, on the tokio runtime of the caller.
+//! `Downloader` wraps it for the callers outside of a runtime
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::OnceCell;
+
+use crate::archive::{self, ARCHIVE};
+use crate::bangumi::{self, PgcTarget};
+use crate::collection::{self, Collection, PlaylistItem};
+use crate::config::{self, QueueConfig, SAVE_PATH};
+use crate::downloader::Batch;
+use crate::error::Error;
+use crate::events::{Event, Subscription};
+use crate::executor::Executor;
+use crate::favorites;
+use crate::helper;
+use crate::manifest::{self, Manifest};
+use crate::pages::{self, Pages};
+use crate::playinfo::Page;
+use crate::process::Progress;
+use crate::space::{self, SpaceFilter};
+use crate::state::State;
+use crate::store::{TaskRecord, JOURNAL};
+use crate::target::Target;
+use crate::task::{self, RangeTiming, Task, TaskOptions};
+
+/// A task of a video in a list
+struct Listed {
+    /// The index of the video in the list
+    video: usize,
+    /// Of a multi-part video
+    page: Option<Page>,
+    target: String,
+    options: TaskOptions,
+}
+
+#[derive(Debug)]
+pub struct AsyncDownloader {
+    // set up on the first id, reading the journal and the cache dirs takes a while
+    id_next: OnceCell<AtomicUsize>,
+    exe: Arc<Executor>,
+    // one connection pool for all the tasks
+    client: reqwest::Client,
+}
+
+impl AsyncDownloader {
+    /// Create a Downloader on the current tokio runtime, panics outside of one.
+    /// It uses the config set before by `config::use_config`
+    /// # Examples
+    /// ```rust
+    /// use core_api::async_downloader::AsyncDownloader;
+    /// # #[tokio::main]
+    /// # async fn main() {
+    /// let dl = AsyncDownloader::new();
+    /// # }
+    /// ```
+    /// The ids start after the ones in the task journal and the `cache_{id}` dirs
+    /// left by the last run. The tasks share one HTTP client built from `config::HttpConfig`
+    pub fn new() -> Self {
+        let exe = Arc::new(Executor::new());
+        Self {
+            id_next: OnceCell::new(),
+            exe,
+            client: helper::client(&config::http()),
+        }
+    }
+
+    /// Run a downloading task
+    /// # Examples
+    /// ```rust
+    /// use core_api::async_downloader::AsyncDownloader;
+    /// use core_api::task::TaskOptions;
+    /// # async fn run() {
+    /// let dl = AsyncDownloader::new();
+    /// let target = "https://www.bilibili.com/video/BV1Ao4y1b7fj/?".to_owned();
+    /// /* A cache dir will be made right beside the `save_dir`,
+    /// and video will be saved at `save_dir`.
+    /// The cache dir will be removed after finished */
+    /// let id = dl.add_task(target, TaskOptions::default()).await.unwrap().id;
+    /// # }
+    /// ```
+    /// `target` could be anything `Target` accepts, such as `BV1Ao4y1b7fj`,
+    /// `av170001` or a `b23.tv` short link. Garbage is rejected before any task is created.
+    ///
+    /// A video in the download archive is reported as `Error::AlreadyDownloaded`
+    /// unless `options.force` is set. The videos of a list or a season are checked when they run,
+    /// and the archived ones finish without downloading
+    pub async fn add_task(&self, target: String, mut options: TaskOptions) -> Result<Batch, Error> {
+        config::save_path()?;
+        let target = match target.parse()? {
+            Target::ShortLink(link) => Target::ShortLink(link).resolve().await?,
+            target => target,
+        };
+        let batch = match target {
+            Target::Favorites(media_id) => self.add_favorites(media_id, options).await?,
+            Target::Collection(collection) => {
+                let collection = collection::list(&self.client, collection).await?;
+                self.add_collection(collection, options).await?
+            }
+            Target::Space(mid) => {
+                self.add_space(mid, &SpaceFilter::default(), options)
+                    .await?
+            }
+            Target::Bangumi(pgc) => match (pgc, options.pages.take()) {
+                (PgcTarget::Episode(ep_id), None) => self
+                    .spawn(bangumi::episode_url(ep_id), options)
+                    .await
+                    .into(),
+                (pgc, pages) => self.add_season(pgc, pages, options).await?,
+            },
+            Target::Video { ref bvid, .. } => {
+                let url = target.url().unwrap();
+                if std::mem::take(&mut options.collection) {
+                    match collection::of_video(&self.client, &url).await {
+                        Ok(Some(collection)) => {
+                            return self.add_collection(collection, options).await
+                        }
+                        Ok(None) => println!("{url} is not in a collection"),
+                        Err(e) => println!("Failed to find the collection of {url}: {e}"),
+                    }
+                }
+                match options.pages.take() {
+                    Some(pages) => self.add_pages(url, pages, options).await?,
+                    None => {
+                        if !options.force && ARCHIVE.contains_bvid(bvid) {
+                            self.check_archive(&url, &options).await?;
+                        }
+                        self.spawn(url, options).await.into()
+                    }
+                }
+            }
+            Target::ShortLink(_) => unreachable!("short links are resolved above"),
+        };
+        Ok(batch)
+    }
+
+    /// Fail with `Error::AlreadyDownloaded` if the stream `url` would download is archived.
+    /// Errors of parsing are left for the task to report
+    async fn check_archive(&self, url: &str, options: &TaskOptions) -> Result<(), Error> {
+        let media = match task::parse(&self.client, url).await {
+            Ok(media) => media,
+            Err(e) => {
+                println!("Failed to check {url} in the download archive: {e}");
+                return Ok(());
+            }
+        };
+        match media.select(&options.policy()) {
+            Some(selection) if ARCHIVE.contains(&archive::key(&media, &selection)) => {
+                Err(Error::AlreadyDownloaded(media.title))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Download every available video in a favorites folder, the pages selected by `options.pages`
+    /// of the multi-part ones, grouped under the returned id. Fails if it can't be listed or is empty
+    pub async fn add_favorites(&self, media_id: u64, options: TaskOptions) -> Result<Batch, Error> {
+        let (info, medias) = favorites::list(&self.client, media_id).await?;
+        let videos: Vec<(String, Option<u32>)> = medias
+            .iter()
+            .map(|media| {
+                (
+                    media.bvid.to_owned(),
+                    Some(media.page).filter(|&page| page > 0),
+                )
+            })
+            .collect();
+        let tasks: Vec<_> = self
+            .expand_videos(&videos, None, &options)
+            .await
+            .into_iter()
+            .map(|listed| (listed.target, listed.options))
+            .collect();
+        if tasks.is_empty() {
+            return Err(Error::EmptyList(format!("favorites {}", info.title)));
+        }
+        Ok(self.spawn_group(info.title, tasks).await)
+    }
+
+    /// Download the uploads of an uploader matching `filter`, newest first,
+    /// the pages selected by `options.pages` of the multi-part ones,
+    /// grouped under the returned id. Fails if they can't be listed or none matches.
+    /// With `filter.incremental`, they're marked synced only once the whole group finished,
+    /// so that the ones failed are listed again by the next run
+    pub async fn add_space(
+        &self,
+        mid: u64,
+        filter: &SpaceFilter,
+        options: TaskOptions,
+    ) -> Result<Batch, Error> {
+        let uploads = space::list(&self.client, mid, filter).await?;
+        let title = match uploads.first() {
+            Some(upload) if !upload.author.is_empty() => upload.author.to_owned(),
+            Some(_) => format!("Space {mid}"),
+            None => return Err(Error::EmptyList(format!("the uploads of {mid}"))),
+        };
+        let videos: Vec<(String, Option<u32>)> = uploads
+            .iter()
+            .map(|upload| (upload.bvid.to_owned(), None))
+            .collect();
+        let tasks: Vec<_> = self
+            .expand_videos(&videos, None, &options)
+            .await
+            .into_iter()
+            .map(|listed| (listed.target, listed.options))
+            .collect();
+        if tasks.is_empty() {
+            return Err(Error::EmptyList(format!("the uploads of {mid}")));
+        }
+        // before the children are spawned, not to miss the group finishing
+        let mut sub = self.subscribe();
+        let batch = self.spawn_group(title, tasks).await;
+        if filter.incremental {
+            let finished = Event::StateChanged {
+                id: batch.id,
+                state: State::Finished.code(),
+            };
+            let newest = uploads[0].clone();
+            tokio::spawn(async move {
+                while let Some(event) = sub.recv().await {
+                    if event == finished {
+                        let _ =
+                            tokio::task::spawn_blocking(move || space::mark_synced(mid, &newest))
+                                .await;
+                        break;
+                    }
+                }
+            });
+        }
+        Ok(batch)
+    }
+
+    /// Compact the journal and find the first free id on the first call
+    async fn next_id(&self) -> usize {
+        let id_next = self
+            .id_next
+            .get_or_init(|| async {
+                let id_next = tokio::task::spawn_blocking(|| {
+                    if let Err(e) = JOURNAL.compact() {
+                        println!("Failed to compact the task journal: {e}");
+                    }
+                    SAVE_PATH
+                        .get()
+                        .map_or(0, |dir| manifest::next_id(dir))
+                        .max(JOURNAL.next_id())
+                })
+                .await
+                .unwrap_or_else(|e| {
+                    println!("Failed to find the next task id: {e}");
+                    0
+                });
+                AtomicUsize::new(id_next)
+            })
+            .await;
+        id_next.fetch_add(1, Ordering::SeqCst)
+    }
+
+    async fn spawn(&self, target: String, options: TaskOptions) -> usize {
+        let id = self.next_id().await;
+        let task = Task::with_client(id, target, options, self.client.clone());
+        self.exe.spawn_task(task).await;
+        id
+    }
+
+    /// Download a collection or series in order, with an index prefix in the file names,
+    /// and write an `.m3u8` playlist next to them.
+    /// The multi-part entries are expanded into the pages selected by `options.pages`
+    async fn add_collection(
+        &self,
+        collection: Collection,
+        options: TaskOptions,
+    ) -> Result<Batch, Error> {
+        if collection.entries.is_empty() {
+            return Err(Error::EmptyList(collection.title));
+        }
+        let total = collection.entries.len();
+        let names: Vec<String> = collection
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| collection::entry_name(i, total, &entry.title))
+            .collect();
+        let videos: Vec<(String, Option<u32>)> = collection
+            .entries
+            .iter()
+            .map(|entry| (entry.bvid.to_owned(), None))
+            .collect();
+        let listed = self.expand_videos(&videos, Some(&names), &options).await;
+        if listed.is_empty() {
+            return Err(Error::EmptyList(collection.title));
+        }
+        let items: Vec<PlaylistItem> = listed
+            .iter()
+            .map(|listed| {
+                let entry = &collection.entries[listed.video];
+                let name = listed.options.name.clone().unwrap_or_default();
+                match &listed.page {
+                    Some(page) => PlaylistItem {
+                        title: format!("{} {}", entry.title, page.part),
+                        duration: page.duration,
+                        name,
+                    },
+                    None => PlaylistItem {
+                        title: entry.title.to_owned(),
+                        duration: entry.duration,
+                        name,
+                    },
+                }
+            })
+            .collect();
+        let tasks = listed
+            .into_iter()
+            .map(|listed| (listed.target, listed.options))
+            .collect();
+        let title = collection.title;
+        let written = match config::save_path() {
+            Ok(save_dir) => {
+                let title = title.clone();
+                tokio::task::spawn_blocking(move || {
+                    Ok(collection::write_playlist(save_dir, &title, &items)?)
+                })
+                .await
+                .unwrap_or_else(|e| Err(e.into()))
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            println!("Failed to write the playlist of {title}: {e}");
+        }
+        Ok(self.spawn_group(title, tasks).await)
+    }
+
+    /// One task per video of a list, or one per page of a multi-part one,
+    /// the pages selected by `options.pages` and all of them by default.
+    /// `videos` are `(bvid, the number of pages if the list tells it)`,
+    /// `names` the file names of the videos if given, a page is named after its video
+    async fn expand_videos(
+        &self,
+        videos: &[(String, Option<u32>)],
+        names: Option<&[String]>,
+        options: &TaskOptions,
+    ) -> Vec<Listed> {
+        let selected = options.pages.clone().unwrap_or(Pages::All);
+        let options = TaskOptions {
+            pages: None,
+            ..options.clone()
+        };
+        let each = pages::select_each(&self.client, videos, &selected).await;
+        let mut tasks = Vec::new();
+        for (video, ((bvid, _), pages)) in videos.iter().zip(each).enumerate() {
+            let name = names.map_or_else(
+                || options.name.clone(),
+                |names| Some(names[video].to_owned()),
+            );
+            let Some((chosen, total)) = pages else {
+                tasks.push(Listed {
+                    video,
+                    page: None,
+                    target: pages::page_url(bvid, 1),
+                    options: TaskOptions {
+                        name,
+                        ..options.clone()
+                    },
+                });
+                continue;
+            };
+            tasks.extend(chosen.into_iter().map(|page| {
+                Listed {
+                    video,
+                    target: pages::page_url(bvid, page.page),
+                    options: TaskOptions {
+                        name: name
+                            .as_ref()
+                            .map(|name| pages::page_title(name, &page, total)),
+                        ..options.clone()
+                    },
+                    page: Some(page),
+                }
+            }));
+        }
+        tasks
+    }
+
+    /// Spawn one task per target, grouped under a new parent id
+    async fn spawn_group(&self, title: String, tasks: Vec<(String, TaskOptions)>) -> Batch {
+        let id = self.next_id().await;
+        let mut children = Vec::with_capacity(tasks.len());
+        for (target, options) in tasks {
+            children.push(self.spawn(target, options).await);
+        }
+        self.exe.group(id, title, children.clone()).await;
+        Batch { id, children }
+    }
+
+    /// Expand a multi-part video into one task per selected page,
+    /// grouped under the returned id. Fails if no page is selected
+    async fn add_pages(
+        &self,
+        target: String,
+        pages: Pages,
+        options: TaskOptions,
+    ) -> Result<Batch, Error> {
+        let video_data = match pages::video_data(&self.client, &target).await {
+            Ok(video_data) => video_data,
+            Err(e) => {
+                println!("Failed to list the pages of {target}: {e}");
+                return Ok(self.spawn(target, options).await.into());
+            }
+        };
+        let tasks: Vec<_> = pages
+            .select(&video_data.pages)
+            .into_iter()
+            .map(|page| {
+                (
+                    pages::page_url(&video_data.bvid, page.page),
+                    options.clone(),
+                )
+            })
+            .collect();
+        if tasks.is_empty() {
+            return Err(Error::EmptyList(format!(
+                "pages {pages} of {}",
+                video_data.title
+            )));
+        }
+        Ok(self.spawn_group(video_data.title, tasks).await)
+    }
+
+    /// Expand a bangumi season into one task per selected episode,
+    /// grouped under the returned id. All episodes if `pages` is `None`,
+    /// fails if none is selected
+    async fn add_season(
+        &self,
+        pgc: PgcTarget,
+        pages: Option<Pages>,
+        options: TaskOptions,
+    ) -> Result<Batch, Error> {
+        let season = match bangumi::season(&self.client, pgc).await {
+            Ok(season) => season,
+            Err(e) => {
+                println!("Failed to list the episodes of {pgc:?}: {e}");
+                return Ok(self
+                    .spawn(Target::Bangumi(pgc).url().unwrap(), options)
+                    .await
+                    .into());
+            }
+        };
+        let pages = pages.unwrap_or(Pages::All);
+        let tasks: Vec<_> = season
+            .episodes
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| pages.contains(*i as u32 + 1))
+            .map(|(_, ep)| (bangumi::episode_url(ep.id), options.clone()))
+            .collect();
+        if tasks.is_empty() {
+            return Err(Error::EmptyList(format!(
+                "episodes {pages} of {}",
+                season.title
+            )));
+        }
+        Ok(self.spawn_group(season.title, tasks).await)
+    }
+
+    /// The unfinished tasks, including the ones of the last runs, in the order of adding.
+    /// An interrupted task of the last run is cancelled, `AsyncDownloader::retry` resumes it
+    pub async fn list_tasks(&self) -> Vec<TaskRecord> {
+        let mut records = Vec::new();
+        for mut record in load_journal().await {
+            record.state = match (
+                self.exe.state(record.id).await,
+                State::from_code(record.state),
+            ) {
+                (404, State::Working | State::Pausing | State::Queued) => State::Cancelled.code(),
+                (404, _) => record.state,
+                (state, _) => state,
+            };
+            if record.state != State::Finished.code() {
+                records.push(record);
+            }
+        }
+        records
+    }
+
+    /// Every task ever added, the latest first
+    pub async fn history(&self) -> Vec<TaskRecord> {
+        let mut records = load_journal().await;
+        records.reverse();
+        records
+    }
+
+    /// The unfinished tasks left by the last run, which could be resumed by `AsyncDownloader::resume`
+    pub async fn resumable(&self) -> Vec<Manifest> {
+        let Some(dir) = SAVE_PATH.get() else {
+            return Vec::new();
+        };
+        tokio::task::spawn_blocking(move || manifest::scan(dir))
+            .await
+            .unwrap_or_default()
+    }
+
+    /// Continue a task left by the last run with its id,
+    /// the expired urls are resolved again and only the missing chunks are downloaded
+    pub async fn resume(&self, id: usize) -> Result<usize, Error> {
+        let save_dir = config::save_path()?;
+        let cache_dir = manifest::cache_dir(save_dir, id);
+        let manifest = tokio::task::spawn_blocking(move || Manifest::load(cache_dir)).await??;
+        self.exe
+            .spawn_task(Task::resume(manifest, self.client.clone()))
+            .await;
+        Ok(id)
+    }
+
+    /// Remove the cache of a task left by the last run
+    pub fn discard(&self, id: usize) -> Result<(), Error> {
+        let save_dir = config::save_path()?;
+        Ok(crate::helper::rm_cache(manifest::cache_dir(save_dir, id))?)
+    }
+
+    /// The children ids of a group, empty if `id` is not a group
+    pub async fn children(&self, id: usize) -> Vec<usize> {
+        self.exe.children(id).await
+    }
+
+    pub async fn title(&self, id: usize) -> String {
+        self.exe.title(id).await
+    }
+
+    /// The chosen stream, such as `1080P60 HEVC`, empty before resolved
+    pub async fn quality(&self, id: usize) -> String {
+        self.exe.quality(id).await
+    }
+
+    /// The CDN hosts a task is downloading from, one for each stream.
+    /// They change when a mirror keeps failing or is too slow
+    pub async fn mirrors(&self, id: usize) -> Vec<String> {
+        self.exe.mirrors(id).await
+    }
+
+    /// How the finished ranges of a task went, for diagnosing slow downloads
+    pub async fn range_timings(&self, id: usize) -> Vec<RangeTiming> {
+        self.exe.range_timings(id).await
+    }
+
+    pub async fn process(&self, id: usize) -> String {
+        self.exe.process(id).await
+    }
+
+    /// The bytes of each stream, the speed, ETA and phase of a task, `None` for an unknown id.
+    /// For a group, summed over the children without the streams
+    pub async fn progress(&self, id: usize) -> Option<Progress> {
+        self.exe.progress(id).await
+    }
+
+    /// Receive the events of the tasks added from now on, instead of polling each of them.
+    /// The subscription ends once the downloader is dropped
+    pub fn subscribe(&self) -> Subscription {
+        self.exe.subscribe()
+    }
+
+    /// Why the task failed or got cancelled, `None` while it's fine.
+    /// For a group, the error of the first failed child
+    pub async fn error(&self, id: usize) -> Option<Error> {
+        self.exe.error(id).await
+    }
+
+    /// 0 working; 1 pausing; 2 cancelled; 3 finished; 4 failed; 5 queued; 404 unknown
+    pub async fn state(&self, id: usize) -> usize {
+        self.exe.state(id).await
+    }
+
+    pub async fn switch(&self, id: usize) {
+        self.exe.switch(id).await;
+    }
+
+    pub async fn cancel(&self, id: usize) {
+        self.exe.cancel(id).await;
+    }
+
+    /// Start a failed or cancelled task, or the ones in a group, again.
+    /// The chunks already downloaded are reused.
+    /// A task of the last run is resumed from its manifest
+    pub async fn retry(&self, id: usize) {
+        if self.exe.state(id).await != 404 {
+            self.exe.retry(id).await;
+        } else if let Err(e) = self.resume(id).await {
+            println!("Failed to resume task {id}: {e}");
+        }
+    }
+
+    /// Tasks of a higher priority are started first, the default is 0.
+    /// For a group, all the children
+    pub async fn set_priority(&self, id: usize, priority: i32) {
+        self.exe.set_priority(id, priority).await;
+    }
+
+    /// Start the queued task, or the children of a group, next
+    pub async fn move_to_top(&self, id: usize) {
+        self.exe.move_to_top(id).await;
+    }
+
+    /// Change how many tasks run at the same time, the running ones keep running
+    pub async fn set_queue_limits(&self, limits: QueueConfig) {
+        self.exe.queue_limits(limits).await;
+    }
+
+    /// Limit the speed of all the tasks to `rate` and of each one to `per_task`,
+    /// in bytes per second, `None` is unlimited.
+    /// The running tasks slow down or speed up without restarting
+    pub async fn set_rate_limit(&self, rate: Option<u64>, per_task: Option<u64>) {
+        self.exe.rate_limit(rate, per_task).await;
+    }
+
+    pub async fn switch_all(&self) {
+        self.exe.switch_all().await;
+    }
+
+    pub async fn terminate(&self) {
+        self.exe.terminate().await;
+    }
+}
+
+/// Read the task journal off the runtime
+async fn load_journal() -> Vec<TaskRecord> {
+    tokio::task::spawn_blocking(|| JOURNAL.load())
+        .await
+        .unwrap_or_default()
+}
+
+impl Default for AsyncDownloader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn within_runtime() {
+        // like a service embedding it, on a runtime of the caller's own
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let dl = AsyncDownloader::new();
+            assert_eq!(dl.state(usize::MAX).await, 404);
+            assert!(dl.children(usize::MAX).await.is_empty());
+            assert_eq!(dl.progress(usize::MAX).await, None);
+            dl.cancel(usize::MAX).await;
+            dl.switch_all().await;
+            let id = dl.spawn_group("empty".to_owned(), vec![]).await.id;
+            assert_eq!(dl.title(id).await, "empty");
+        });
+    }
+}
@@ -0,0 +1,240 @@
+//! Bangumi / PGC content (anime, documentaries, movies),
+//! `/bangumi/play/ep…`, `/bangumi/play/ss…` and `/bangumi/media/md…`
+
+use serde::Deserialize;
+
+use crate::error::Error;
+use crate::helper;
+use crate::playinfo::{ParsedMedia, PlayUrl, VideoData};
+
+type PgcResult<T> = Result<T, Error>;
+
+/// What a bangumi url points to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PgcTarget {
+    Episode(u64),
+    Season(u64),
+    Media(u64),
+}
+
+impl PgcTarget {
+    /// Return `None` if `url` is not a bangumi url
+    pub fn from_url(url: &str) -> Option<Self> {
+        let re = regex::Regex::new(r"/bangumi/(?:play|media)/(ep|ss|md)(\d+)").unwrap();
+        let cpt = re.captures(url)?;
+        let id = cpt.get(2)?.as_str().parse().ok()?;
+        match cpt.get(1)?.as_str() {
+            "ep" => Some(PgcTarget::Episode(id)),
+            "ss" => Some(PgcTarget::Season(id)),
+            _ => Some(PgcTarget::Media(id)),
+        }
+    }
+}
+
+/// The response of `pgc` apis, which put the content in `result` instead of `data`
+#[derive(Deserialize, Debug)]
+struct PgcResponse<T> {
+    code: i64,
+    #[serde(default)]
+    message: String,
+    result: Option<T>,
+}
+
+impl<T> PgcResponse<T> {
+    fn result(self) -> PgcResult<T> {
+        match (self.code, self.result) {
+            (0, Some(result)) => Ok(result),
+            (code, _) => Err(Error::from_api(code, &self.message)),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Season {
+    pub season_id: u64,
+    /// The name of the show
+    pub title: String,
+    #[serde(default)]
+    pub season_title: String,
+    /// All seasons of the show, including this one
+    #[serde(default)]
+    pub seasons: Vec<SeasonRef>,
+    #[serde(default)]
+    pub episodes: Vec<Episode>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct SeasonRef {
+    pub season_id: u64,
+    #[serde(default)]
+    pub season_title: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Episode {
+    /// The ep id
+    pub id: u64,
+    #[serde(default)]
+    pub aid: u64,
+    #[serde(default)]
+    pub bvid: String,
+    #[serde(default)]
+    pub cid: u64,
+    /// Usually the episode number
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub long_title: String,
+    /// Such as `会员` for VIP-only episodes
+    #[serde(default)]
+    pub badge: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct MediaInfo {
+    media: MediaRef,
+}
+
+#[derive(Deserialize, Debug)]
+struct MediaRef {
+    season_id: u64,
+}
+
+/// The playurl of an episode, `is_preview` is set if only a trial is offered
+#[derive(Deserialize, Debug)]
+struct PgcPlayUrl {
+    #[serde(flatten)]
+    play_url: PlayUrl,
+    #[serde(default)]
+    is_preview: u8,
+}
+
+impl Season {
+    /// Starts from 1, by the position in all seasons of the show
+    pub fn season_number(&self) -> usize {
+        self.seasons
+            .iter()
+            .position(|s| s.season_id == self.season_id)
+            .map_or(1, |i| i + 1)
+    }
+
+    /// Such as `Title - S01 E02 - The long title`
+    pub fn episode_title(&self, index: usize) -> String {
+        let ep = &self.episodes[index];
+        let name = match ep.long_title.is_empty() {
+            true => &ep.title,
+            false => &ep.long_title,
+        };
+        format!(
+            "{} - S{:02} E{:02} - {name}",
+            self.title,
+            self.season_number(),
+            index + 1
+        )
+    }
+}
+
+pub(crate) fn episode_url(ep_id: u64) -> String {
+    format!("https://www.bilibili.com/bangumi/play/ep{ep_id}")
+}
+
+/// Fetch the season the target belongs to
+pub(crate) async fn season(client: &reqwest::Client, target: PgcTarget) -> PgcResult<Season> {
+    let query = match target {
+        PgcTarget::Episode(ep_id) => format!("ep_id={ep_id}"),
+        PgcTarget::Season(season_id) => format!("season_id={season_id}"),
+        PgcTarget::Media(media_id) => {
+            let resp: PgcResponse<MediaInfo> = helper::get_json(
+                client,
+                &format!("https://api.bilibili.com/pgc/review/user?media_id={media_id}"),
+            )
+            .await?;
+            format!("season_id={}", resp.result()?.media.season_id)
+        }
+    };
+    let resp: PgcResponse<Season> = helper::get_json(
+        client,
+        &format!("https://api.bilibili.com/pgc/view/web/season?{query}"),
+    )
+    .await?;
+    resp.result()
+}
+
+/// Parse an episode, the first one of the season if `target` is not an episode
+pub(crate) async fn parse(client: &reqwest::Client, target: PgcTarget) -> PgcResult<ParsedMedia> {
+    let season = season(client, target).await?;
+    let index = match target {
+        PgcTarget::Episode(ep_id) => season.episodes.iter().position(|ep| ep.id == ep_id),
+        _ => (!season.episodes.is_empty()).then_some(0),
+    }
+    .ok_or_else(|| Error::ParseFailed("episode not found in the season".to_owned()))?;
+    let ep = &season.episodes[index];
+    let resp: PgcResponse<PgcPlayUrl> = helper::get_json(
+        client,
+        &format!(
+        "https://api.bilibili.com/pgc/player/web/playurl?ep_id={}&cid={}&qn=127&fnval=4048&fourk=1",
+        ep.id, ep.cid
+    ),
+    )
+    .await?;
+    let play_url = resp.result()?;
+    if play_url.is_preview != 0 {
+        return Err(Error::VipRequired(
+            "only a preview is offered, 大会员专享".to_owned(),
+        ));
+    }
+    let title = season.episode_title(index);
+    Ok(ParsedMedia {
+        title: helper::file_name_filter(&title),
+        page: 1,
+        video_data: VideoData {
+            bvid: ep.bvid.to_owned(),
+            aid: ep.aid,
+            cid: ep.cid,
+            title,
+            ..Default::default()
+        },
+        play_url: play_url.play_url,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pgc_target() {
+        let target = PgcTarget::from_url("https://www.bilibili.com/bangumi/play/ep374717?from=x");
+        assert_eq!(target, Some(PgcTarget::Episode(374717)));
+        let target = PgcTarget::from_url("https://www.bilibili.com/bangumi/play/ss33378");
+        assert_eq!(target, Some(PgcTarget::Season(33378)));
+        let target = PgcTarget::from_url("https://www.bilibili.com/bangumi/media/md28229899/");
+        assert_eq!(target, Some(PgcTarget::Media(28229899)));
+        let target = PgcTarget::from_url("https://www.bilibili.com/video/BV1Ao4y1b7fj/");
+        assert_eq!(target, None);
+    }
+
+    #[test]
+    fn name_episode() {
+        let json = r#"{"code":0,"message":"success","result":{"season_id":2,"title":"Show","season_title":"第二季","seasons":[{"season_id":1},{"season_id":2}],"episodes":[{"id":10,"title":"1","long_title":"Start"},{"id":11,"title":"2","long_title":""}]}}"#;
+        let resp: PgcResponse<Season> = serde_json::from_str(json).unwrap();
+        let season = resp.result().unwrap();
+        assert_eq!(season.season_number(), 2);
+        assert_eq!(season.episode_title(0), "Show - S02 E01 - Start");
+        assert_eq!(season.episode_title(1), "Show - S02 E02 - 2");
+    }
+
+    #[test]
+    fn vip_and_region() {
+        let json = r#"{"code":-10403,"message":"抱歉您所在地区不可观看！"}"#;
+        let resp: PgcResponse<Season> = serde_json::from_str(json).unwrap();
+        assert!(resp
+            .result()
+            .unwrap_err()
+            .to_string()
+            .contains("region-locked"));
+        let json = r#"{"code":-10403,"message":"大会员专享限制"}"#;
+        let resp: PgcResponse<Season> = serde_json::from_str(json).unwrap();
+        assert!(resp.result().unwrap_err().to_string().contains("VIP"));
+    }
+}
@@ -0,0 +1,267 @@
+//! Collections (合集, `ugc_season`) and series (系列) of an uploader,
+//! downloaded in order with an index prefix and an `.m3u8` playlist
+
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+use crate::config::VIDEO_FORMAT;
+use crate::error::Error;
+use crate::helper::{self, ApiResponse};
+use crate::playinfo::{InitialState, UgcSeason};
+
+type CollectionResult<T> = Result<T, Error>;
+
+/// Items per request, the max the apis allow
+const PAGE_SIZE: usize = 100;
+
+/// A collection or series list url
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CollectionTarget {
+    /// 合集, `space.bilibili.com/{mid}/channel/collectiondetail?sid={season_id}`
+    Season { mid: u64, id: u64 },
+    /// 系列, `space.bilibili.com/{mid}/channel/seriesdetail?sid={series_id}`
+    Series { mid: u64, id: u64 },
+}
+
+impl CollectionTarget {
+    /// Return `None` if `url` is not a collection or series url.
+    /// The newer `space.bilibili.com/{mid}/lists/{id}?type=season|series` is accepted too
+    pub fn from_url(url: &str) -> Option<Self> {
+        let re = regex::Regex::new(
+            r"space\.bilibili\.com/(\d+)/(?:channel/(collection|series)detail\?(?:.*&)?sid=(\d+)|lists/(\d+)\?(?:.*&)?type=(season|series))",
+        )
+        .unwrap();
+        let cpt = re.captures(url)?;
+        let mid = cpt.get(1)?.as_str().parse().ok()?;
+        let (kind, id) = match (cpt.get(2), cpt.get(3)) {
+            (Some(kind), Some(id)) => (kind.as_str(), id.as_str()),
+            _ => (cpt.get(5)?.as_str(), cpt.get(4)?.as_str()),
+        };
+        let id = id.parse().ok()?;
+        match kind {
+            "collection" | "season" => Some(CollectionTarget::Season { mid, id }),
+            _ => Some(CollectionTarget::Series { mid, id }),
+        }
+    }
+}
+
+/// An ordered list of videos
+#[derive(Debug, Clone, Default)]
+pub struct Collection {
+    pub title: String,
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Entry {
+    pub bvid: String,
+    pub title: String,
+    /// In seconds
+    #[serde(default)]
+    pub duration: u64,
+}
+
+impl From<UgcSeason> for Collection {
+    fn from(season: UgcSeason) -> Self {
+        let entries = season
+            .sections
+            .into_iter()
+            .flat_map(|section| section.episodes)
+            .map(|ep| Entry {
+                bvid: ep.bvid,
+                title: ep.title,
+                duration: ep.page.map_or(0, |p| p.duration),
+            })
+            .collect();
+        Self {
+            title: season.title,
+            entries,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct Archives {
+    #[serde(default)]
+    archives: Vec<Entry>,
+    #[serde(default)]
+    meta: Option<Meta>,
+    page: ArchivesPage,
+}
+
+#[derive(Deserialize, Debug)]
+struct Meta {
+    name: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ArchivesPage {
+    total: usize,
+}
+
+#[derive(Deserialize, Debug)]
+struct SeriesInfo {
+    meta: Meta,
+}
+
+/// The collection the video `target` points to belongs to, `None` if it's not in one
+pub(crate) async fn of_video(
+    client: &reqwest::Client,
+    target: &str,
+) -> CollectionResult<Option<Collection>> {
+    let html = helper::get_html(client, target).await?;
+    let state = InitialState::from_html(&html)?;
+    Ok(state.video_data.ugc_season.map(Collection::from))
+}
+
+/// Page through a collection or series, in order
+pub(crate) async fn list(
+    client: &reqwest::Client,
+    target: CollectionTarget,
+) -> CollectionResult<Collection> {
+    let mut collection = Collection::default();
+    if let CollectionTarget::Series { id, .. } = target {
+        let resp: ApiResponse<SeriesInfo> = helper::get_json(
+            client,
+            &format!("https://api.bilibili.com/x/series/series?series_id={id}"),
+        )
+        .await?;
+        collection.title = resp.data()?.meta.name;
+    }
+    let mut pn = 1;
+    loop {
+        let url = match target {
+            CollectionTarget::Season { mid, id } => format!(
+                "https://api.bilibili.com/x/polymer/web-space/seasons_archives_list?mid={mid}&season_id={id}&sort_reverse=false&page_num={pn}&page_size={PAGE_SIZE}"
+            ),
+            CollectionTarget::Series { mid, id } => format!(
+                "https://api.bilibili.com/x/series/archives?mid={mid}&series_id={id}&only_normal=true&sort=asc&pn={pn}&ps={PAGE_SIZE}"
+            ),
+        };
+        let resp: ApiResponse<Archives> = helper::get_json(client, &url).await?;
+        let page = resp.data()?;
+        if let Some(meta) = page.meta {
+            collection.title = meta.name;
+        }
+        let gotten = page.archives.len();
+        collection.entries.extend(page.archives);
+        if gotten == 0 || collection.entries.len() >= page.page.total {
+            break Ok(collection);
+        }
+        pn += 1;
+    }
+}
+
+/// The file name of the `index`th entry, such as `03 - title`
+pub(crate) fn entry_name(index: usize, total: usize, title: &str) -> String {
+    let width = total.to_string().len().max(2);
+    format!("{:0width$} - {title}", index + 1)
+}
+
+/// A video in the playlist, a page of a multi-part entry or the entry itself
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PlaylistItem {
+    pub title: String,
+    /// In seconds
+    pub duration: u64,
+    /// The file name it's saved as
+    pub name: String,
+}
+
+/// Write `{title}.m3u8` in `dir`, listing the `items` in order
+pub(crate) fn write_playlist(
+    dir: &str,
+    title: &str,
+    items: &[PlaylistItem],
+) -> std::io::Result<PathBuf> {
+    let mut m3u8 = String::from("#EXTM3U\n");
+    for item in items {
+        m3u8.push_str(&format!(
+            "#EXTINF:{},{}\n{}.{VIDEO_FORMAT}\n",
+            item.duration,
+            item.title,
+            helper::file_name_filter(&item.name)
+        ));
+    }
+    std::fs::create_dir_all(dir)?;
+    let path = Path::new(dir).join(format!("{}.m3u8", helper::file_name_filter(title)));
+    std::fs::write(&path, m3u8)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collection_target() {
+        let target = CollectionTarget::from_url(
+            "https://space.bilibili.com/32280488/channel/collectiondetail?sid=1234&ctype=0",
+        );
+        assert_eq!(
+            target,
+            Some(CollectionTarget::Season {
+                mid: 32280488,
+                id: 1234
+            })
+        );
+        let target = CollectionTarget::from_url(
+            "https://space.bilibili.com/32280488/channel/seriesdetail?sid=5678",
+        );
+        assert_eq!(
+            target,
+            Some(CollectionTarget::Series {
+                mid: 32280488,
+                id: 5678
+            })
+        );
+        let target = CollectionTarget::from_url(
+            "https://space.bilibili.com/32280488/lists/1234?type=season",
+        );
+        assert_eq!(
+            target,
+            Some(CollectionTarget::Season {
+                mid: 32280488,
+                id: 1234
+            })
+        );
+        assert_eq!(
+            CollectionTarget::from_url("https://space.bilibili.com/32280488"),
+            None
+        );
+    }
+
+    #[test]
+    fn playlist() {
+        let json = r#"{"id":1,"title":"My/Collection","sections":[{"title":"s","episodes":[{"bvid":"BV1","title":"first","page":{"cid":1,"page":1,"duration":61}},{"bvid":"BV2","title":"second"}]}]}"#;
+        let season: UgcSeason = serde_json::from_str(json).unwrap();
+        let collection = Collection::from(season);
+        assert_eq!(collection.entries.len(), 2);
+        let names: Vec<String> = collection
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(i, e)| entry_name(i, collection.entries.len(), &e.title))
+            .collect();
+        assert_eq!(names[1], "02 - second");
+        let items: Vec<PlaylistItem> = collection
+            .entries
+            .iter()
+            .zip(&names)
+            .map(|(entry, name)| PlaylistItem {
+                title: entry.title.to_owned(),
+                duration: entry.duration,
+                name: name.to_owned(),
+            })
+            .collect();
+        let dir = std::env::temp_dir().join("bili_playlist_test");
+        let path = write_playlist(dir.to_str().unwrap(), &collection.title, &items).unwrap();
+        assert!(path.ends_with("MyCollection.m3u8"));
+        let m3u8 = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            m3u8,
+            "#EXTM3U\n#EXTINF:61,first\n01 - first.mp4\n#EXTINF:0,second\n02 - second.mp4\n"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -6,13 +6,21 @@ use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
 use std::env;
 
+use crate::error::Error;
 use crate::helper;
+use crate::quality::QualityPolicy;
+use crate::retry::RetryPolicy;
 
 pub(crate) const USER_AGENT: &str = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/16.5 Safari/605.1.15";
 pub(crate) static COOKIE: OnceCell<String> = OnceCell::new();
 pub(crate) static PARTS: OnceCell<usize> = OnceCell::new();
 pub(crate) static SAVE_PATH: OnceCell<String> = OnceCell::new();
 pub(crate) static FFMPEG: OnceCell<String> = OnceCell::new();
+pub(crate) static QUALITY: OnceCell<QualityPolicy> = OnceCell::new();
+pub(crate) static HTTP: OnceCell<HttpConfig> = OnceCell::new();
+pub(crate) static QUEUE: OnceCell<QueueConfig> = OnceCell::new();
+pub(crate) static RETRY: OnceCell<RetryPolicy> = OnceCell::new();
+pub(crate) static VERIFY: OnceCell<bool> = OnceCell::new();
 pub(crate) static USER: once_cell::sync::Lazy<String> =
     once_cell::sync::Lazy::new(|| match env::var("USERNAME") {
         Ok(user) => user,
@@ -20,9 +28,75 @@ pub(crate) static USER: once_cell::sync::Lazy<String> =
     });
 pub(crate) const VIDEO_FORMAT: &str = "mp4";
 pub(crate) const AUDIO_FORMAT: &str = "aac";
-pub(crate) const MINI_SIZE: usize = 5_000_000; // 5MB per req
-pub(crate) static TIME_RETRY: once_cell::sync::Lazy<u64> =
-    once_cell::sync::Lazy::new(|| (MINI_SIZE * PARTS.get().unwrap() / 500_000) as u64);
+pub(crate) const WRITE_SIZE: usize = 1_000_000; // 1MB per write
+pub(crate) const SAVE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2); // between manifest saves
+
+/// How the shared HTTP client connects
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct HttpConfig {
+    /// Idle connections kept alive per host
+    pub pool_size: usize,
+    /// In seconds
+    pub connect_timeout: u64,
+    /// In seconds, how long to wait for the next bytes of a range before requesting it again
+    pub read_timeout: u64,
+    /// Use HTTP/2 if the server offers it, otherwise only HTTP/1.1
+    pub http2: bool,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            pool_size: 16,
+            connect_timeout: 10,
+            read_timeout: 30,
+            http2: true,
+        }
+    }
+}
+
+/// The applied `HttpConfig`, or the default one
+pub(crate) fn http() -> HttpConfig {
+    HTTP.get().cloned().unwrap_or_default()
+}
+
+/// How many queued tasks the executor runs at the same time
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct QueueConfig {
+    pub max_active: usize,
+    /// Of all the active tasks, each opens `PARTS` for a stream
+    pub max_connections: usize,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            max_active: 3,
+            max_connections: 32,
+        }
+    }
+}
+
+/// The applied `QueueConfig`, or the default one
+pub(crate) fn queue() -> QueueConfig {
+    QUEUE.get().cloned().unwrap_or_default()
+}
+
+/// The applied `RetryPolicy`, or the default one
+pub(crate) fn retry() -> RetryPolicy {
+    RETRY.get().cloned().unwrap_or_default()
+}
+
+/// If the merged files are checked, on by default
+pub(crate) fn verify() -> bool {
+    VERIFY.get().copied().unwrap_or(true)
+}
+
+fn verify_by_default() -> bool {
+    true
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 struct Config {
@@ -30,65 +104,167 @@ struct Config {
     save_path: String,
     parts: usize,
     ffmpeg: String,
+    #[serde(default)]
+    quality: QualityPolicy,
+    #[serde(default)]
+    http: HttpConfig,
+    #[serde(default)]
+    queue: QueueConfig,
+    #[serde(default)]
+    retry: RetryPolicy,
+    #[serde(default = "verify_by_default")]
+    verify: bool,
 }
 
 impl Config {
+    /// The values are set once, applying again keeps the first ones
     fn apply(&self) {
-        COOKIE.set(self.cookie.to_owned()).unwrap();
-        SAVE_PATH.set(self.save_path.to_owned()).unwrap();
-        PARTS.set(self.parts).unwrap();
-        FFMPEG.set(self.ffmpeg.to_owned()).unwrap();
+        let _ = COOKIE.set(self.cookie.to_owned());
+        let _ = SAVE_PATH.set(self.save_path.to_owned());
+        let _ = PARTS.set(self.parts);
+        let _ = FFMPEG.set(self.ffmpeg.to_owned());
+        let _ = QUALITY.set(self.quality.to_owned());
+        let _ = HTTP.set(self.http.to_owned());
+        let _ = QUEUE.set(self.queue.to_owned());
+        let _ = RETRY.set(self.retry.to_owned());
+        let _ = VERIFY.set(self.verify);
     }
-}
 
-#[allow(unused)]
-pub fn use_config() {
-    let config = Entry::new("bilibili downloader", &USER)
-        .unwrap()
-        .get_password();
-    match &config {
-        Ok(config) => {
-            let config = serde_json::from_str::<Config>(config).unwrap();
-            println!("{config:?}");
-            config.apply();
-        }
-        Err(_) => {
-            let config = Config {
+    fn entry() -> crate::Result<Entry> {
+        Entry::new("bilibili downloader", &USER).map_err(|e| Error::Config(e.to_string()))
+    }
+
+    /// Read the config saved in keyring, or the default one
+    fn load() -> crate::Result<Self> {
+        match Self::entry()?.get_password() {
+            Ok(config) => Ok(serde_json::from_str::<Config>(&config)?),
+            Err(keyring::Error::NoEntry) => Ok(Config {
                 cookie: String::new(),
-                save_path: helper::download_dir().to_str().unwrap().to_owned(),
+                save_path: helper::download_dir().to_string_lossy().into_owned(),
                 parts: 1,
                 ffmpeg: String::from("ffmpeg"),
-            };
-            config.apply();
+                quality: QualityPolicy::default(),
+                http: HttpConfig::default(),
+                queue: QueueConfig::default(),
+                retry: RetryPolicy::default(),
+                verify: true,
+            }),
+            Err(e) => Err(Error::Config(e.to_string())),
         }
     }
+
+    fn save(&self) -> crate::Result<()> {
+        let config_json = serde_json::to_string(self)?;
+        Self::entry()?
+            .set_password(&config_json)
+            .map_err(|e| Error::Config(e.to_string()))
+    }
+}
+
+/// Where the videos and the caches go, an error until the config is applied
+pub(crate) fn save_path() -> crate::Result<&'static str> {
+    SAVE_PATH
+        .get()
+        .map(String::as_str)
+        .ok_or_else(|| Error::Config("the save path is not set".to_owned()))
+}
+
+pub fn use_config() -> crate::Result<()> {
+    let config = Config::load()?;
+    println!("{config:?}");
+    config.apply();
+    Ok(())
 }
 
-pub fn submit_config(cookie: String, save_path: String, parts: usize, ffmpeg: String) {
+pub fn submit_config(
+    cookie: String,
+    save_path: String,
+    parts: usize,
+    ffmpeg: String,
+) -> crate::Result<()> {
     let config = Config {
         cookie,
         save_path,
         parts,
         ffmpeg,
+        ..Config::load()?
     };
-    let config_json = serde_json::to_string(&config).unwrap();
-    let entry = Entry::new("bilibili downloader", &USER).unwrap();
-    entry.set_password(&config_json).unwrap();
-}
-
-pub fn read_config() -> (String, String, usize, String) {
-    let config = Entry::new("bilibili downloader", &USER)
-        .unwrap()
-        .get_password();
-    let config = match &config {
-        Ok(config) => serde_json::from_str::<Config>(config).unwrap(),
-        Err(_) => Config {
-            cookie: String::new(),
-            save_path: helper::download_dir().to_str().unwrap().to_owned(),
-            parts: 1,
-            ffmpeg: String::from("ffmpeg"),
-        },
-    };
+    config.save()
+}
+
+pub fn read_config() -> crate::Result<(String, String, usize, String)> {
+    let config = Config::load()?;
     config.apply();
-    (config.cookie, config.save_path, config.parts, config.ffmpeg)
+    Ok((config.cookie, config.save_path, config.parts, config.ffmpeg))
+}
+
+/// Save the default `QualityPolicy`, used by the tasks added after restarting
+/// unless another one is passed to `Downloader::add_task`.
+/// The config is applied once at startup, the running tasks keep the old one
+pub fn submit_quality(quality: QualityPolicy) -> crate::Result<()> {
+    let config = Config {
+        quality,
+        ..Config::load()?
+    };
+    config.save()
+}
+
+pub fn read_quality() -> crate::Result<QualityPolicy> {
+    Ok(Config::load()?.quality)
+}
+
+/// Save the `HttpConfig`, used after restarting.
+/// The client of a running downloader is built from the one applied at startup
+pub fn submit_http(http: HttpConfig) -> crate::Result<()> {
+    let config = Config {
+        http,
+        ..Config::load()?
+    };
+    config.save()
+}
+
+pub fn read_http() -> crate::Result<HttpConfig> {
+    Ok(Config::load()?.http)
+}
+
+/// Save the `QueueConfig`, used after restarting,
+/// `Downloader::set_queue_limits` changes a running one
+pub fn submit_queue(queue: QueueConfig) -> crate::Result<()> {
+    let config = Config {
+        queue,
+        ..Config::load()?
+    };
+    config.save()
+}
+
+pub fn read_queue() -> crate::Result<QueueConfig> {
+    Ok(Config::load()?.queue)
+}
+
+/// Save the `RetryPolicy`, used after restarting.
+/// The running tasks keep retrying by the one applied at startup
+pub fn submit_retry(retry: RetryPolicy) -> crate::Result<()> {
+    let config = Config {
+        retry,
+        ..Config::load()?
+    };
+    config.save()
+}
+
+pub fn read_retry() -> crate::Result<RetryPolicy> {
+    Ok(Config::load()?.retry)
+}
+
+/// Save if the merged files are checked after downloading, used after restarting.
+/// Until then the tasks go by the value applied at startup
+pub fn submit_verify(verify: bool) -> crate::Result<()> {
+    let config = Config {
+        verify,
+        ..Config::load()?
+    };
+    config.save()
+}
+
+pub fn read_verify() -> crate::Result<bool> {
+    Ok(Config::load()?.verify)
 }
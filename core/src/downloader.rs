@@ -1,17 +1,44 @@
 //! Downloader
-//! Ask executor to control tasks
+//! Block on `AsyncDownloader` with a runtime of its own, for the callers outside of one
 
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use serde::Serialize;
 
-// use crate::config;
-use crate::executor::Executor;
-use crate::task::Task;
+use crate::async_downloader::AsyncDownloader;
+use crate::config::QueueConfig;
+use crate::error::Error;
+use crate::events::Subscription;
+use crate::helper;
+use crate::manifest::Manifest;
+use crate::process::Progress;
+use crate::space::SpaceFilter;
+use crate::store::TaskRecord;
+use crate::task::{RangeTiming, TaskOptions};
 
+/// What `AsyncDownloader::add_task` spawned
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Batch {
+    /// The task id, or the parent id if the target was expanded into a group
+    pub id: usize,
+    /// Empty if the target was not expanded
+    pub children: Vec<usize>,
+}
+
+impl From<usize> for Batch {
+    fn from(id: usize) -> Self {
+        Self {
+            id,
+            children: Vec::new(),
+        }
+    }
+}
+
+/// The blocking `AsyncDownloader`, each method waits for the one of the same name.
+/// Use `AsyncDownloader` instead within a tokio runtime, blocking there panics
 #[derive(Debug)]
 pub struct Downloader {
-    id_next: AtomicUsize,
-    exe: Arc<Executor>,
+    inner: AsyncDownloader,
+    // runs the executor and the tasks
+    rt: tokio::runtime::Runtime,
 }
 
 impl Downloader {
@@ -23,58 +50,140 @@ impl Downloader {
     /// let dl = Downloader::new();
     /// ```
     pub fn new() -> Self {
-        // config::use_config();
-        let exe = Arc::new(Executor::new());
-        Self {
-            id_next: AtomicUsize::new(0),
-            exe,
-        }
+        let rt = helper::create_rt();
+        let inner = {
+            let _guard = rt.enter();
+            AsyncDownloader::new()
+        };
+        Self { inner, rt }
     }
 
     /// Run a downloading task
     /// # Examples
     /// ```rust
     /// use core_api::downloader::Downloader;
+    /// use core_api::task::TaskOptions;
     /// let dl = Downloader::new();
     /// let target = "https://www.bilibili.com/video/BV1Ao4y1b7fj/?".to_owned();
     /// /* A cache dir will be made right beside the `save_dir`,
     /// and video will be saved at `save_dir`.
     /// The cache dir will be removed after finished */
-    /// let id = dl.add_task(target);
+    /// let id = dl.add_task(target, TaskOptions::default()).unwrap().id;
     /// ```
-    pub fn add_task(&self, target: String) -> usize {
-        let id = self.id_next.fetch_add(1, Ordering::SeqCst);
-        let task = Task::new(id, target);
-        self.exe.spawn_task(task);
-        id
+    pub fn add_task(&self, target: String, options: TaskOptions) -> Result<Batch, Error> {
+        self.rt.block_on(self.inner.add_task(target, options))
+    }
+
+    pub fn add_favorites(&self, media_id: u64, options: TaskOptions) -> Result<Batch, Error> {
+        self.rt
+            .block_on(self.inner.add_favorites(media_id, options))
+    }
+
+    pub fn add_space(
+        &self,
+        mid: u64,
+        filter: &SpaceFilter,
+        options: TaskOptions,
+    ) -> Result<Batch, Error> {
+        self.rt.block_on(self.inner.add_space(mid, filter, options))
+    }
+
+    pub fn list_tasks(&self) -> Vec<TaskRecord> {
+        self.rt.block_on(self.inner.list_tasks())
+    }
+
+    pub fn history(&self) -> Vec<TaskRecord> {
+        self.rt.block_on(self.inner.history())
+    }
+
+    pub fn resumable(&self) -> Vec<Manifest> {
+        self.rt.block_on(self.inner.resumable())
+    }
+
+    pub fn resume(&self, id: usize) -> Result<usize, Error> {
+        self.rt.block_on(self.inner.resume(id))
+    }
+
+    pub fn discard(&self, id: usize) -> Result<(), Error> {
+        self.inner.discard(id)
+    }
+
+    pub fn children(&self, id: usize) -> Vec<usize> {
+        self.rt.block_on(self.inner.children(id))
     }
 
     pub fn title(&self, id: usize) -> String {
-        self.exe.title(id)
+        self.rt.block_on(self.inner.title(id))
+    }
+
+    pub fn quality(&self, id: usize) -> String {
+        self.rt.block_on(self.inner.quality(id))
+    }
+
+    pub fn mirrors(&self, id: usize) -> Vec<String> {
+        self.rt.block_on(self.inner.mirrors(id))
+    }
+
+    pub fn range_timings(&self, id: usize) -> Vec<RangeTiming> {
+        self.rt.block_on(self.inner.range_timings(id))
     }
 
     pub fn process(&self, id: usize) -> String {
-        self.exe.process(id)
+        self.rt.block_on(self.inner.process(id))
+    }
+
+    pub fn progress(&self, id: usize) -> Option<Progress> {
+        self.rt.block_on(self.inner.progress(id))
     }
 
+    /// Receive with `Subscription::blocking_recv` on a thread of its own
+    pub fn subscribe(&self) -> Subscription {
+        self.inner.subscribe()
+    }
+
+    pub fn error(&self, id: usize) -> Option<Error> {
+        self.rt.block_on(self.inner.error(id))
+    }
+
+    /// 0 working; 1 pausing; 2 cancelled; 3 finished; 4 failed; 5 queued; 404 unknown
     pub fn state(&self, id: usize) -> usize {
-        self.exe.state(id)
+        self.rt.block_on(self.inner.state(id))
     }
 
     pub fn switch(&self, id: usize) {
-        self.exe.switch(id);
+        self.rt.block_on(self.inner.switch(id))
     }
 
     pub fn cancel(&self, id: usize) {
-        self.exe.cancel(id);
+        self.rt.block_on(self.inner.cancel(id))
+    }
+
+    pub fn retry(&self, id: usize) {
+        self.rt.block_on(self.inner.retry(id))
+    }
+
+    pub fn set_priority(&self, id: usize, priority: i32) {
+        self.rt.block_on(self.inner.set_priority(id, priority))
+    }
+
+    pub fn move_to_top(&self, id: usize) {
+        self.rt.block_on(self.inner.move_to_top(id))
+    }
+
+    pub fn set_queue_limits(&self, limits: QueueConfig) {
+        self.rt.block_on(self.inner.set_queue_limits(limits))
+    }
+
+    pub fn set_rate_limit(&self, rate: Option<u64>, per_task: Option<u64>) {
+        self.rt.block_on(self.inner.set_rate_limit(rate, per_task))
     }
 
     pub fn switch_all(&self) {
-        self.exe.switch_all();
+        self.rt.block_on(self.inner.switch_all())
     }
 
     pub fn terminate(&self) {
-        self.exe.terminate();
+        self.rt.block_on(self.inner.terminate())
     }
 }
 
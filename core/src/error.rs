@@ -0,0 +1,175 @@
+//! The errors of core-api
+//! A failed task keeps its error, query it by `Downloader::error`
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", content = "detail")]
+pub enum Error {
+    /// Requesting failed or timed out
+    Network(String),
+    /// A page or an api response is not in the expected form
+    ParseFailed(String),
+    /// The cookie in config is missing or expired
+    LoginRequired(String),
+    /// Only VIP (大会员) could watch it
+    VipRequired(String),
+    /// Not available in your area
+    RegionLocked(String),
+    /// No stream matches the `QualityPolicy`
+    NoStream,
+    /// Any other non-zero code of `api.bilibili.com`
+    Api {
+        code: i64,
+        message: String,
+    },
+    /// Reading or saving the config failed
+    Config(String),
+    Io(String),
+    FfmpegFailed(String),
+    Cancelled,
+    /// The target is not a video, bangumi or list url
+    InvalidTarget(String),
+    /// The chosen stream is in the download archive, pass `force` to download it again
+    AlreadyDownloaded(String),
+    /// The signed stream urls have expired, and parsing again didn't give usable ones
+    UrlExpired,
+    /// The downloaded bytes don't add up, or the merged file is broken
+    Corrupted(String),
+    /// In bytes, the streams and the merged file don't fit on the disk
+    InsufficientSpace {
+        needed: u64,
+        available: u64,
+    },
+    /// A favorites folder, space or collection has nothing to download
+    EmptyList(String),
+}
+
+impl Error {
+    /// Classify a non-zero code of `api.bilibili.com`
+    pub(crate) fn from_api(code: i64, message: &str) -> Self {
+        let detail = format!("{code}: {message}");
+        if code == -101 {
+            Error::LoginRequired(detail)
+        } else if message.contains("地区") {
+            Error::RegionLocked(detail)
+        } else if message.contains("会员") {
+            Error::VipRequired(detail)
+        } else {
+            Error::Api {
+                code,
+                message: message.to_owned(),
+            }
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Network(e) => write!(f, "network error: {e}"),
+            Error::ParseFailed(e) => write!(f, "failed to parse: {e}"),
+            Error::LoginRequired(e) => {
+                write!(f, "login required, check the cookie in config ({e})")
+            }
+            Error::VipRequired(e) => {
+                write!(f, "VIP (大会员) required, check the cookie in config ({e})")
+            }
+            Error::RegionLocked(e) => write!(f, "region-locked, not available in your area ({e})"),
+            Error::NoStream => write!(f, "no stream matches the quality policy"),
+            Error::Api { code, message } => write!(f, "api error ({code}: {message})"),
+            Error::Config(e) => write!(f, "config error: {e}"),
+            Error::Io(e) => write!(f, "io error: {e}"),
+            Error::FfmpegFailed(e) => write!(f, "ffmpeg failed: {e}"),
+            Error::Cancelled => write!(f, "cancelled"),
+            Error::InvalidTarget(e) => write!(f, "{e}"),
+            Error::AlreadyDownloaded(title) => write!(f, "already downloaded: {title}"),
+            Error::UrlExpired => write!(f, "the stream urls have expired"),
+            Error::Corrupted(e) => write!(f, "the downloaded file is corrupted: {e}"),
+            Error::InsufficientSpace { needed, available } => write!(
+                f,
+                "not enough disk space, {:.1}MB needed but {:.1}MB available",
+                *needed as f64 / 1_000_000.0,
+                *available as f64 / 1_000_000.0
+            ),
+            Error::EmptyList(list) => write!(f, "nothing to download in {list}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        match e.is_decode() {
+            true => Error::ParseFailed(e.to_string()),
+            false => Error::Network(e.to_string()),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::ParseFailed(e.to_string())
+    }
+}
+
+impl From<crate::target::TargetError> for Error {
+    fn from(e: crate::target::TargetError) -> Self {
+        Error::InvalidTarget(e.to_string())
+    }
+}
+
+impl From<tokio::task::JoinError> for Error {
+    fn from(e: tokio::task::JoinError) -> Self {
+        match e.is_cancelled() {
+            true => Error::Cancelled,
+            false => Error::Io(format!("a downloading part panicked: {e}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_api_codes() {
+        assert!(matches!(
+            Error::from_api(-101, "账号未登录"),
+            Error::LoginRequired(_)
+        ));
+        assert!(matches!(
+            Error::from_api(-10403, "抱歉您所在地区不可观看！"),
+            Error::RegionLocked(_)
+        ));
+        assert!(matches!(
+            Error::from_api(-10403, "大会员专享限制"),
+            Error::VipRequired(_)
+        ));
+        assert_eq!(
+            Error::from_api(-404, "啥都木有"),
+            Error::Api {
+                code: -404,
+                message: "啥都木有".to_owned()
+            }
+        );
+    }
+
+    #[test]
+    fn serialize() {
+        let json = serde_json::to_string(&Error::NoStream).unwrap();
+        assert_eq!(json, r#"{"kind":"NoStream"}"#);
+        let json = serde_json::to_string(&Error::Io("denied".to_owned())).unwrap();
+        assert_eq!(json, r#"{"kind":"Io","detail":"denied"}"#);
+    }
+}
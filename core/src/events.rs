@@ -0,0 +1,146 @@
+//! The events of a downloader, pushed to the subscribers instead of being polled.
+//! Subscribe by `Downloader::subscribe`
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::error::Error;
+use crate::process::Progress;
+
+/// Events kept for a subscriber falling behind, it misses the older ones
+const CAPACITY: usize = 1024;
+
+/// The events of a task, and `StateChanged` and `Progress` of the group it belongs to
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind")]
+pub enum Event {
+    /// Queued in the executor
+    TaskAdded {
+        id: usize,
+        target: String,
+    },
+    /// The title is known once the target is parsed
+    TitleResolved {
+        id: usize,
+        title: String,
+    },
+    /// Every second while the task runs
+    Progress {
+        id: usize,
+        progress: Progress,
+        /// The hosts in use, one for each stream
+        mirrors: Vec<String>,
+    },
+    /// The new code of `Downloader::state`
+    StateChanged {
+        id: usize,
+        state: usize,
+    },
+    Failed {
+        id: usize,
+        error: Error,
+    },
+    /// Saved at `path`
+    Finished {
+        id: usize,
+        path: String,
+        /// Not downloaded, it's in the download archive and was saved at `path` before
+        skipped: bool,
+    },
+}
+
+impl Event {
+    /// The task the event is about
+    pub fn id(&self) -> usize {
+        match self {
+            Event::TaskAdded { id, .. }
+            | Event::TitleResolved { id, .. }
+            | Event::Progress { id, .. }
+            | Event::StateChanged { id, .. }
+            | Event::Failed { id, .. }
+            | Event::Finished { id, .. } => *id,
+        }
+    }
+}
+
+/// Sends the events to the subscribers, they're dropped if there is none
+#[derive(Debug, Clone)]
+pub(crate) struct Events(broadcast::Sender<Event>);
+
+impl Default for Events {
+    fn default() -> Self {
+        Self(broadcast::channel(CAPACITY).0)
+    }
+}
+
+impl Events {
+    pub fn send(&self, event: Event) {
+        let _ = self.0.send(event);
+    }
+
+    pub fn subscribe(&self) -> Subscription {
+        Subscription(self.0.subscribe())
+    }
+}
+
+/// The events sent after subscribing.
+/// A subscriber too slow to keep up skips the oldest ones rather than holding up the tasks
+#[derive(Debug)]
+pub struct Subscription(broadcast::Receiver<Event>);
+
+impl Subscription {
+    /// The next event, `None` once the downloader is dropped
+    pub async fn recv(&mut self) -> Option<Event> {
+        loop {
+            match self.0.recv().await {
+                Ok(event) => return Some(event),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    println!("Missed {missed} events");
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// Like `recv`, for a thread outside of the async runtime
+    pub fn blocking_recv(&mut self) -> Option<Event> {
+        loop {
+            match self.0.blocking_recv() {
+                Ok(event) => return Some(event),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    println!("Missed {missed} events");
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn broadcast() {
+        let events = Events::default();
+        // nobody listens, it's dropped
+        events.send(Event::StateChanged { id: 0, state: 5 });
+        let mut first = events.subscribe();
+        let mut second = events.subscribe();
+        events.send(Event::TitleResolved {
+            id: 1,
+            title: "title".to_owned(),
+        });
+        for sub in [&mut first, &mut second] {
+            let event = sub.blocking_recv().unwrap();
+            assert_eq!(event.id(), 1);
+        }
+        let json = serde_json::to_value(Event::StateChanged { id: 2, state: 3 }).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"kind": "StateChanged", "id": 2, "state": 3})
+        );
+        drop(events);
+        assert_eq!(first.blocking_recv(), None);
+    }
+}
@@ -1,134 +1,504 @@
-//! Create a thread and spawn tasks through `add task` on it, then run them asynchronously
+//! Spawn a message loop on the runtime of the caller, and run the tasks added through it asynchronously
 
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 
-use crate::{helper, message::Message, task::Task};
+use crate::{
+    config,
+    error::Error,
+    events::{Event, Events, Subscription},
+    limiter::RateLimiter,
+    message::Message,
+    process::Progress,
+    scheduler::Scheduler,
+    state::State,
+    task::{RangeTiming, Task},
+};
 
 #[derive(Debug)]
 pub struct Executor {
     tx: mpsc::Sender<Message>,
-    rt: tokio::runtime::Runtime,
+    events: Events,
 }
 
 impl Executor {
+    /// Must be called within a tokio runtime, the loop and the tasks are spawned on it.
+    /// The loop ends when the executor is dropped
     pub fn new() -> Self {
         let (tx, mut rx) = mpsc::channel(8);
-        std::thread::spawn(move || {
-            let rt = helper::create_rt();
-            rt.block_on(async move {
-                let mut tasks = HashMap::new();
-                while let Some(msg) = rx.recv().await {
-                    match msg {
-                        // spawn a download
-                        Message::Job(task) => {
-                            let task = Arc::new(task);
-                            tasks.insert(task.id, task.clone());
-                            let task_c = task.clone();
-                            tokio::spawn(async move { task_c.execute().await });
+        // weak, so that the loop still ends when the executor is dropped
+        let done_tx = tx.downgrade();
+        let events = Events::default();
+        let events_c = events.clone();
+        tokio::spawn(async move {
+            let events = events_c;
+            let mut tasks: HashMap<usize, Arc<Task>> = HashMap::new();
+            // parent id -> (title, children ids)
+            let mut groups: HashMap<usize, (String, Vec<usize>)> = HashMap::new();
+            // shared by every range worker, and the cap of each task
+            let global = Arc::new(RateLimiter::default());
+            let mut per_task = None;
+            let mut scheduler = Scheduler::new(config::queue());
+            // the ids a message on `id` applies to, all children if it's a group
+            let members =
+                |groups: &HashMap<usize, (String, Vec<usize>)>, id: usize| match groups.get(&id) {
+                    Some((_, children)) => children.clone(),
+                    None => vec![id],
+                };
+            // child id -> parent id
+            let mut parents: HashMap<usize, usize> = HashMap::new();
+            // parent id -> the state last sent for it
+            let mut group_states: HashMap<usize, State> = HashMap::new();
+            // the children tell their own changes, the groups are told from them
+            let mut own_events = events.subscribe();
+            let mut group_ticker = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                let msg = tokio::select! {
+                    msg = rx.recv() => match msg {
+                        Some(msg) => msg,
+                        None => break,
+                    },
+                    Some(event) = own_events.recv() => {
+                        let Event::StateChanged { id, .. } = event else {
+                            continue;
+                        };
+                        let Some(parent) = parents.get(&id).copied() else {
+                            continue;
+                        };
+                        let state = children_state(&tasks, &groups[&parent].1);
+                        if group_states.insert(parent, state) != Some(state) {
+                            events.send(Event::StateChanged {
+                                id: parent,
+                                state: state.code(),
+                            });
                         }
-                        // query the process
-                        Message::Process((tx, id)) => {
-                            let process = match tasks.get(&id) {
-                                Some(task) => task.process(),
-                                None => format!("Unknown id {}", id),
-                            };
-                            tx.send(process).unwrap();
+                        continue;
+                    }
+                    _ = group_ticker.tick() => {
+                        for (parent, (_, children)) in groups.iter() {
+                            if group_states.get(parent) == Some(&State::Working) {
+                                events.send(Event::Progress {
+                                    id: *parent,
+                                    progress: children_progress(&tasks, children),
+                                    mirrors: Vec::new(),
+                                });
+                            }
                         }
-                        // query state
-                        Message::State((tx, id)) => {
-                            let state_code = match tasks.get(&id) {
-                                Some(task) => task.state(),
-                                None => 404,
-                            };
-                            tx.send(state_code).unwrap();
+                        continue;
+                    }
+                };
+                match msg {
+                    // spawn a download
+                    Message::Job(mut task) => {
+                        task.limit_by(global.clone());
+                        task.set_rate_limit(per_task);
+                        task.notify(events.clone());
+                        task.save_record();
+                        events.send(Event::TaskAdded {
+                            id: task.id,
+                            target: task.target().to_owned(),
+                        });
+                        let task: Arc<Task> = Arc::from(task);
+                        tasks.insert(task.id, task.clone());
+                        scheduler.push(task.id);
+                    }
+                    // group the tasks expanded from one target
+                    Message::Group((parent, title, children)) => {
+                        for child in children.iter() {
+                            parents.insert(*child, parent);
                         }
-                        // query title
-                        Message::Title((tx, id)) => {
-                            let title = match tasks.get(&id) {
-                                Some(task) => task.title(),
-                                None => format!("Unknown id {}", id),
+                        group_states.insert(parent, children_state(&tasks, &children));
+                        groups.insert(parent, (title, children));
+                    }
+                    // query the children of a group
+                    Message::Children((tx, id)) => {
+                        let children = match groups.get(&id) {
+                            Some((_, children)) => children.clone(),
+                            None => Vec::new(),
+                        };
+                        // the asker might have given up
+                        let _ = tx.send(children);
+                    }
+                    // query the process
+                    Message::Process((tx, id)) => {
+                        let process = match tasks.get(&id) {
+                            Some(task) => task.process(),
+                            None => format!("Unknown id {}", id),
+                        };
+                        let _ = tx.send(process);
+                    }
+                    // query the bytes, speed and phase
+                    // summed over the children for a group
+                    Message::Progress((tx, id)) => {
+                        let progress = match (tasks.get(&id), groups.get(&id)) {
+                            (Some(task), _) => Some(task.progress()),
+                            (None, Some((_, children))) => {
+                                Some(children_progress(&tasks, children))
+                            }
+                            (None, None) => None,
+                        };
+                        let _ = tx.send(progress);
+                    }
+                    // query state
+                    Message::State((tx, id)) => {
+                        let state_code = match (tasks.get(&id), groups.get(&id)) {
+                            (Some(task), _) => task.state(),
+                            (None, Some((_, children))) => children_state(&tasks, children).code(),
+                            (None, None) => 404,
+                        };
+                        let _ = tx.send(state_code);
+                    }
+                    // query title
+                    Message::Title((tx, id)) => {
+                        let title = match (tasks.get(&id), groups.get(&id)) {
+                            (Some(task), _) => task.title(),
+                            (None, Some((title, _))) => title.to_owned(),
+                            (None, None) => format!("Unknown id {}", id),
+                        };
+                        let _ = tx.send(title);
+                    }
+                    // query the chosen stream
+                    Message::Quality((tx, id)) => {
+                        let quality = match tasks.get(&id) {
+                            Some(task) => task.quality(),
+                            None => format!("Unknown id {}", id),
+                        };
+                        let _ = tx.send(quality);
+                    }
+                    // query the mirrors in use
+                    Message::Mirrors((tx, id)) => {
+                        let mirrors = match tasks.get(&id) {
+                            Some(task) => task.mirrors(),
+                            None => Vec::new(),
+                        };
+                        let _ = tx.send(mirrors);
+                    }
+                    // query how the ranges went
+                    Message::Timings((tx, id)) => {
+                        let timings = match tasks.get(&id) {
+                            Some(task) => task.range_timings(),
+                            None => Vec::new(),
+                        };
+                        let _ = tx.send(timings);
+                    }
+                    // query why it failed, the first failed child for a group
+                    Message::Error((tx, id)) => {
+                        let error = match groups.get(&id) {
+                            Some((_, children)) => children
+                                .iter()
+                                .filter_map(|id| tasks.get(id).and_then(|t| t.error()))
+                                .find(|e| *e != Error::Cancelled),
+                            None => tasks.get(&id).and_then(|t| t.error()),
+                        };
+                        let _ = tx.send(error);
+                    }
+                    // cancel a download, kept for retrying
+                    Message::Cancel(id) => {
+                        for id in members(&groups, id) {
+                            scheduler.remove(id);
+                            match tasks.get(&id) {
+                                Some(task) => task.cancel(),
+                                None => println!("Unknown id {}", id),
                             };
-                            tx.send(title).unwrap();
                         }
-                        // cancel a download
-                        Message::Cancel(id) => {
-                            match tasks.remove(&id) {
+                    }
+                    // queue a failed or cancelled download again
+                    Message::Retry(id) => {
+                        for id in members(&groups, id) {
+                            match tasks.get(&id) {
                                 Some(task) => {
-                                    task.cancel();
+                                    if task.retry() {
+                                        scheduler.push(id);
+                                    }
                                 }
                                 None => println!("Unknown id {}", id),
                             };
                         }
-                        Message::Switch(id) => {
+                    }
+                    Message::Switch(id) => {
+                        for id in members(&groups, id) {
                             match tasks.get(&id) {
                                 Some(task) => task.switch(),
                                 None => println!("Unknown id {}", id),
                             };
                         }
-                        Message::SwitchAll => {
-                            for task in tasks.values() {
-                                task.switch();
-                            }
+                    }
+                    // change the speed limits of the running tasks too
+                    Message::RateLimit((rate, task_rate)) => {
+                        global.set_rate(rate);
+                        per_task = task_rate;
+                        for task in tasks.values() {
+                            task.set_rate_limit(per_task);
                         }
-                        Message::Terminate => {
-                            for task in tasks.values() {
-                                task.cancel();
-                            }
+                    }
+                    Message::Priority((id, priority)) => {
+                        for id in members(&groups, id) {
+                            scheduler.set_priority(id, priority);
+                        }
+                    }
+                    Message::Top(id) => {
+                        // keep the order of the children
+                        for id in members(&groups, id).into_iter().rev() {
+                            scheduler.move_to_top(id);
+                        }
+                    }
+                    Message::QueueLimits(limits) => scheduler.set_limits(limits),
+                    Message::Done(id) => scheduler.finish(id),
+                    Message::SwitchAll => {
+                        for task in tasks.values() {
+                            task.switch();
+                        }
+                    }
+                    Message::Terminate => {
+                        for task in tasks.values() {
+                            task.cancel();
                         }
                     }
                 }
-            });
+                // start what the limits allow now
+                while let Some(id) =
+                    scheduler.next(|id| tasks.get(&id).map_or(0, |t| t.connections()))
+                {
+                    let Some(task) = tasks.get(&id) else {
+                        scheduler.finish(id);
+                        continue;
+                    };
+                    if task.now() != State::Queued {
+                        scheduler.finish(id);
+                        continue;
+                    }
+                    let task_c = task.clone();
+                    let done_tx = done_tx.clone();
+                    tokio::spawn(async move {
+                        let _ = task_c.execute().await;
+                        if let Some(tx) = done_tx.upgrade() {
+                            let _ = tx.send(Message::Done(task_c.id)).await;
+                        }
+                    });
+                }
+            }
             println!("Terminated");
         });
-        Self {
-            tx,
-            rt: helper::create_rt(),
-        }
+        Self { tx, events }
     }
 
-    pub fn spawn_task(&self, task: Task) {
-        self.rt.block_on(self.tx.send(Message::Job(task))).unwrap();
+    /// Ask the loop, `None` if it has ended
+    async fn ask<T>(&self, msg: impl FnOnce(oneshot::Sender<T>) -> Message) -> Option<T> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(msg(tx)).await.ok()?;
+        rx.await.ok()
     }
 
-    pub fn process(&self, id: usize) -> String {
-        let (tx, rx) = tokio::sync::oneshot::channel();
-        self.rt
-            .block_on(self.tx.send(Message::Process((tx, id))))
-            .unwrap();
-        self.rt.block_on(rx).unwrap()
+    pub async fn spawn_task(&self, task: Task) {
+        let _ = self.tx.send(Message::Job(Box::new(task))).await;
     }
 
-    pub fn switch(&self, id: usize) {
-        self.rt.block_on(self.tx.send(Message::Switch(id))).unwrap();
+    /// Group the `children` tasks under `parent`
+    pub async fn group(&self, parent: usize, title: String, children: Vec<usize>) {
+        let _ = self
+            .tx
+            .send(Message::Group((parent, title, children)))
+            .await;
     }
 
-    pub fn switch_all(&self) {
-        self.rt.block_on(self.tx.send(Message::SwitchAll)).unwrap();
+    pub async fn children(&self, id: usize) -> Vec<usize> {
+        self.ask(|tx| Message::Children((tx, id)))
+            .await
+            .unwrap_or_default()
     }
 
-    pub fn cancel(&self, id: usize) {
-        self.rt.block_on(self.tx.send(Message::Cancel(id))).unwrap();
+    /// The events of every task added after subscribing
+    pub fn subscribe(&self) -> Subscription {
+        self.events.subscribe()
     }
 
-    pub fn state(&self, id: usize) -> usize {
-        let (tx, rx) = tokio::sync::oneshot::channel();
-        self.rt
-            .block_on(self.tx.send(Message::State((tx, id))))
-            .unwrap();
-        self.rt.block_on(rx).unwrap()
+    pub async fn process(&self, id: usize) -> String {
+        self.ask(|tx| Message::Process((tx, id)))
+            .await
+            .unwrap_or_default()
+    }
+
+    pub async fn progress(&self, id: usize) -> Option<Progress> {
+        self.ask(|tx| Message::Progress((tx, id))).await.flatten()
+    }
+
+    pub async fn switch(&self, id: usize) {
+        let _ = self.tx.send(Message::Switch(id)).await;
+    }
+
+    pub async fn switch_all(&self) {
+        let _ = self.tx.send(Message::SwitchAll).await;
     }
 
-    pub fn title(&self, id: usize) -> String {
-        let (tx, rx) = tokio::sync::oneshot::channel();
-        self.rt
-            .block_on(self.tx.send(Message::Title((tx, id))))
+    pub async fn cancel(&self, id: usize) {
+        let _ = self.tx.send(Message::Cancel(id)).await;
+    }
+
+    pub async fn retry(&self, id: usize) {
+        let _ = self.tx.send(Message::Retry(id)).await;
+    }
+
+    pub async fn set_priority(&self, id: usize, priority: i32) {
+        let _ = self.tx.send(Message::Priority((id, priority))).await;
+    }
+
+    pub async fn move_to_top(&self, id: usize) {
+        let _ = self.tx.send(Message::Top(id)).await;
+    }
+
+    pub async fn queue_limits(&self, limits: config::QueueConfig) {
+        let _ = self.tx.send(Message::QueueLimits(limits)).await;
+    }
+
+    pub async fn rate_limit(&self, rate: Option<u64>, per_task: Option<u64>) {
+        let _ = self.tx.send(Message::RateLimit((rate, per_task))).await;
+    }
+
+    pub async fn state(&self, id: usize) -> usize {
+        self.ask(|tx| Message::State((tx, id))).await.unwrap_or(404)
+    }
+
+    pub async fn title(&self, id: usize) -> String {
+        self.ask(|tx| Message::Title((tx, id)))
+            .await
+            .unwrap_or_default()
+    }
+
+    pub async fn quality(&self, id: usize) -> String {
+        self.ask(|tx| Message::Quality((tx, id)))
+            .await
+            .unwrap_or_default()
+    }
+
+    pub async fn mirrors(&self, id: usize) -> Vec<String> {
+        self.ask(|tx| Message::Mirrors((tx, id)))
+            .await
+            .unwrap_or_default()
+    }
+
+    pub async fn range_timings(&self, id: usize) -> Vec<RangeTiming> {
+        self.ask(|tx| Message::Timings((tx, id)))
+            .await
+            .unwrap_or_default()
+    }
+
+    pub async fn error(&self, id: usize) -> Option<Error> {
+        self.ask(|tx| Message::Error((tx, id))).await.flatten()
+    }
+
+    pub async fn terminate(&self) {
+        let _ = self.tx.send(Message::Terminate).await;
+    }
+}
+
+fn children_state(tasks: &HashMap<usize, Arc<Task>>, children: &[usize]) -> State {
+    let states: Vec<State> = children
+        .iter()
+        .filter_map(|id| tasks.get(id).map(|t| t.now()))
+        .collect();
+    group_state(&states)
+}
+
+fn children_progress(tasks: &HashMap<usize, Arc<Task>>, children: &[usize]) -> Progress {
+    let progresses: Vec<Progress> = children
+        .iter()
+        .filter_map(|id| tasks.get(id).map(|t| t.progress()))
+        .collect();
+    Progress::sum(&progresses)
+}
+
+/// The state of a group: working if any child is working, then pausing,
+/// finished if all children finished, failed if any child failed or there is none,
+/// otherwise cancelled
+fn group_state(states: &[State]) -> State {
+    if states.is_empty() {
+        State::Failed
+    } else if states.contains(&State::Working) {
+        State::Working
+    } else if states.contains(&State::Pausing) {
+        State::Pausing
+    } else if states.contains(&State::Queued) {
+        State::Queued
+    } else if states.iter().all(|&s| s == State::Finished) {
+        State::Finished
+    } else if states.contains(&State::Failed) {
+        State::Failed
+    } else {
+        State::Cancelled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{COOKIE, SAVE_PATH};
+    use crate::task::TaskOptions;
+
+    #[test]
+    fn group_states() {
+        // nothing listed
+        use State::*;
+        assert_eq!(group_state(&[]), Failed);
+        assert_eq!(group_state(&[Finished, Finished]), Finished);
+        assert_eq!(group_state(&[Finished, Cancelled, Failed]), Failed);
+        assert_eq!(group_state(&[Finished, Queued, Working]), Working);
+    }
+
+    #[test]
+    fn given_up() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
             .unwrap();
-        self.rt.block_on(rx).unwrap()
+        rt.block_on(async {
+            let exe = Executor::new();
+            // asked, then dropped before the loop answers
+            tokio::select! {
+                biased;
+                _ = exe.title(1) => panic!("answered before the loop ran"),
+                _ = async {} => {}
+            }
+            assert_eq!(exe.state(1).await, 404);
+        });
     }
 
-    pub fn terminate(&self) {
-        self.rt.block_on(self.tx.send(Message::Terminate)).unwrap();
+    #[test]
+    fn group_events() {
+        let _ = SAVE_PATH.set(std::env::temp_dir().to_str().unwrap().to_owned());
+        let _ = COOKIE.set(String::new());
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let exe = Executor::new();
+            let mut sub = exe.subscribe();
+            let children = vec![900_001, 900_002];
+            for id in children.iter() {
+                // refused at once
+                let target = "http://127.0.0.1:1/".to_owned();
+                exe.spawn_task(Task::new(*id, target, TaskOptions::default()))
+                    .await;
+            }
+            let parent = 900_000;
+            exe.group(parent, "group".to_owned(), children).await;
+            exe.cancel(parent).await;
+            // told by the children, the group has no task of its own
+            let changed = async {
+                loop {
+                    if let Some(Event::StateChanged { id, state }) = sub.recv().await {
+                        if id == parent && state != State::Working.code() {
+                            return state;
+                        }
+                    }
+                }
+            };
+            let state = tokio::time::timeout(Duration::from_secs(10), changed)
+                .await
+                .unwrap();
+            assert_eq!(state, State::Cancelled.code());
+            assert_eq!(exe.state(parent).await, state);
+        });
     }
 }
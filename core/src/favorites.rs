@@ -0,0 +1,120 @@
+//! Favorites folder (收藏夹), list every video in it
+
+use serde::Deserialize;
+
+use crate::error::Error;
+use crate::helper::{self, ApiResponse};
+
+type FavResult<T> = Result<T, Error>;
+
+/// Items per request, the max the api allows
+const PAGE_SIZE: usize = 20;
+
+/// Find the media_id in a favorites folder url, such as
+/// `space.bilibili.com/{mid}/favlist?fid={media_id}` or `bilibili.com/medialist/detail/ml{media_id}`.
+/// A bare `ml{media_id}` is accepted too
+pub fn media_id(target: &str) -> Option<u64> {
+    let re = regex::Regex::new(r"(?:/favlist\?(?:.*&)?fid=|(?:^|/)ml)(\d+)").unwrap();
+    re.captures(target.trim())?.get(1)?.as_str().parse().ok()
+}
+
+#[derive(Deserialize, Debug)]
+struct FavPage {
+    info: FavInfo,
+    medias: Option<Vec<FavMedia>>,
+    #[serde(default)]
+    has_more: bool,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct FavInfo {
+    pub id: u64,
+    pub title: String,
+    #[serde(default)]
+    pub media_count: usize,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct FavMedia {
+    /// The aid for videos
+    pub id: u64,
+    /// 2 for videos, 12 for audios, 21 for collections
+    #[serde(rename = "type")]
+    pub kind: u32,
+    pub title: String,
+    #[serde(default)]
+    pub bvid: String,
+    /// The lowest bit is set if the video is deleted or invisible
+    #[serde(default)]
+    pub attr: u32,
+    /// The number of pages (分P)
+    #[serde(default)]
+    pub page: u32,
+}
+
+impl FavMedia {
+    /// A video still visible
+    pub fn available(&self) -> bool {
+        self.kind == 2 && self.attr & 1 == 0 && self.title != "已失效视频" && !self.bvid.is_empty()
+    }
+}
+
+/// Page through a favorites folder, return its info and the available videos in it
+pub(crate) async fn list(
+    client: &reqwest::Client,
+    media_id: u64,
+) -> FavResult<(FavInfo, Vec<FavMedia>)> {
+    let mut medias = Vec::new();
+    let mut pn = 1;
+    loop {
+        let resp: ApiResponse<FavPage> = helper::get_json(client, &format!(
+            "https://api.bilibili.com/x/v3/fav/resource/list?media_id={media_id}&pn={pn}&ps={PAGE_SIZE}&platform=web"
+        ))
+        .await?;
+        let page = resp.data()?;
+        let gotten = page.medias.unwrap_or_default();
+        let skipped = gotten.iter().filter(|m| !m.available()).count();
+        if skipped > 0 {
+            println!("Skip {skipped} unavailable items in favorites {media_id} page {pn}");
+        }
+        medias.extend(gotten.into_iter().filter(FavMedia::available));
+        if !page.has_more {
+            break Ok((page.info, medias));
+        }
+        pn += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_media_id() {
+        let id =
+            media_id("https://space.bilibili.com/32280488/favlist?fid=1052622027&ftype=create");
+        assert_eq!(id, Some(1052622027));
+        let id = media_id("https://www.bilibili.com/medialist/detail/ml1052622027?type=1");
+        assert_eq!(id, Some(1052622027));
+        assert_eq!(media_id("ml1052622027"), Some(1052622027));
+        assert_eq!(
+            media_id("https://www.bilibili.com/video/BV1Ao4y1b7fj/"),
+            None
+        );
+    }
+
+    #[test]
+    fn skip_unavailable() {
+        let json = r#"{"code":0,"message":"0","data":{"info":{"id":1,"title":"fav","media_count":3},"medias":[{"id":1,"type":2,"title":"ok","bvid":"BV1","attr":0,"page":1},{"id":2,"type":2,"title":"已失效视频","bvid":"BV2","attr":9,"page":1},{"id":3,"type":12,"title":"audio","bvid":"","attr":0}],"has_more":false}}"#;
+        let resp: ApiResponse<FavPage> = serde_json::from_str(json).unwrap();
+        let page = resp.data().unwrap();
+        let available: Vec<_> = page
+            .medias
+            .unwrap()
+            .into_iter()
+            .filter(FavMedia::available)
+            .collect();
+        assert_eq!(available.len(), 1);
+        assert_eq!(available[0].bvid, "BV1");
+    }
+}
@@ -1,14 +1,76 @@
 //! A header generation
+//! Cuts a stream into the ranges its workers request.
+//! A range is sized to take about `TARGET_TIME` at the speed measured so far,
+//! and near the end an idle worker takes over half of the biggest range left
 
-use crate::config::{MINI_SIZE, USER_AGENT};
+use crate::config::USER_AGENT;
 use reqwest::header;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+/// In bytes, smaller ranges cost more in requests than they save
+pub(crate) const MIN_CHUNK: usize = 512 * 1024;
+pub(crate) const MAX_CHUNK: usize = 64 * 1024 * 1024;
+/// Before any range has finished
+const FIRST_CHUNK: usize = 4 * 1024 * 1024;
+/// In seconds, how long a range should take
+const TARGET_TIME: f64 = 4.0;
 
 #[derive(Debug)]
-pub struct HeadersGen {
-    start: AtomicUsize,
-    to: AtomicUsize,
+struct Active {
     end: usize,
+    // the next byte to arrive
+    offset: usize,
+}
+
+#[derive(Debug, Default)]
+struct Ranges {
+    // `(start, end)` not handed out yet, inclusive
+    pending: VecDeque<(usize, usize)>,
+    // by the id handed out with the range
+    active: HashMap<usize, Active>,
+    next_id: usize,
+    // bytes per second of a worker, 0 before measured
+    speed: f64,
+}
+
+impl Ranges {
+    /// Last about `TARGET_TIME`, but leave a share of the rest to every worker
+    fn chunk_size(&self, workers: usize) -> usize {
+        let left: usize = self
+            .pending
+            .iter()
+            .map(|(start, end)| end - start + 1)
+            .sum();
+        let size = match self.speed > 0.0 {
+            true => (self.speed * TARGET_TIME) as usize,
+            false => FIRST_CHUNK,
+        };
+        size.min(left / workers.max(1)).clamp(MIN_CHUNK, MAX_CHUNK)
+    }
+
+    /// Cut the back half off the range with the most bytes to come
+    fn steal(&mut self) -> Option<(usize, usize)> {
+        let victim = self
+            .active
+            .values_mut()
+            .max_by_key(|a| (a.end + 1).saturating_sub(a.offset))?;
+        let left = (victim.end + 1).saturating_sub(victim.offset);
+        if left < 2 * MIN_CHUNK {
+            return None;
+        }
+        let end = victim.end;
+        let mid = victim.offset + left / 2;
+        victim.end = mid - 1;
+        Some((mid, end))
+    }
+}
+
+#[derive(Debug)]
+pub struct HeadersGen {
+    ranges: Mutex<Ranges>,
+    workers: usize,
 }
 
 fn headers(range: &str) -> header::HeaderMap {
@@ -30,25 +92,74 @@ fn headers(range: &str) -> header::HeaderMap {
 }
 
 impl HeadersGen {
-    // input a range
-    // return an iterator contains several headers
-    pub fn new(start: usize, end: usize) -> Self {
+    /// The whole stream of `total` bytes, shared by `workers`
+    #[cfg(test)]
+    pub fn new(total: usize, workers: usize) -> Self {
+        Self::skipping(
+            match total {
+                0 => Vec::new(),
+                total => vec![(0, total - 1)],
+            },
+            workers,
+        )
+    }
+
+    /// Only the `holes` left by the last run, `(start, end)` inclusive
+    pub fn skipping(holes: Vec<(usize, usize)>, workers: usize) -> Self {
         Self {
-            start: AtomicUsize::new(start),
-            to: AtomicUsize::new(std::cmp::min(start + MINI_SIZE, end)),
-            end,
+            ranges: Mutex::new(Ranges {
+                pending: holes.into(),
+                ..Default::default()
+            }),
+            workers,
         }
     }
 
-    pub fn next(&self) -> Option<header::HeaderMap> {
-        let to = self.to.fetch_add(MINI_SIZE, Ordering::Relaxed);
-        let start = self.start.swap(to + 1, Ordering::Relaxed);
-        if start > self.end {
-            return None;
+    fn lock(&self) -> MutexGuard<'_, Ranges> {
+        self.ranges.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The id and the headers of the next range, `None` once there's nothing left to share.
+    /// The worker reports on the range by the id
+    pub fn next(&self) -> Option<(usize, header::HeaderMap)> {
+        let mut ranges = self.lock();
+        let size = ranges.chunk_size(self.workers);
+        let (start, end) = match ranges.pending.pop_front() {
+            // leave no tail smaller than a chunk
+            Some((start, end)) if end - start + 1 >= size + MIN_CHUNK => {
+                ranges.pending.push_front((start + size, end));
+                (start, start + size - 1)
+            }
+            Some(range) => range,
+            None => ranges.steal()?,
+        };
+        let id = ranges.next_id;
+        ranges.next_id += 1;
+        ranges.active.insert(id, Active { end, offset: start });
+        Some((id, headers(&format!("{start}-{end}"))))
+    }
+
+    /// The bytes of range `id` before `offset` have arrived, return where the range ends now.
+    /// It ends earlier once an idle worker has taken over the rest
+    pub fn progress(&self, id: usize, offset: usize) -> Option<usize> {
+        let mut ranges = self.lock();
+        let active = ranges.active.get_mut(&id)?;
+        active.offset = active.offset.max(offset);
+        Some(active.end)
+    }
+
+    /// Range `id` of `bytes` took `elapsed`, the next ranges are sized by the speed
+    pub fn finish(&self, id: usize, bytes: usize, elapsed: Duration) {
+        let mut ranges = self.lock();
+        ranges.active.remove(&id);
+        if elapsed.is_zero() {
+            return;
         }
-        let range = format!("{}-{}", start, std::cmp::min(to, self.end));
-        let hm = headers(&range);
-        Some(hm)
+        let speed = bytes as f64 / elapsed.as_secs_f64();
+        ranges.speed = match ranges.speed > 0.0 {
+            true => 0.7 * ranges.speed + 0.3 * speed,
+            false => speed,
+        };
     }
 }
 
@@ -56,9 +167,60 @@ impl HeadersGen {
 mod tests {
     use super::*;
 
+    fn range(headers: &header::HeaderMap) -> (usize, usize) {
+        let range = headers.get("Range").unwrap().to_str().unwrap();
+        let (start, end) = range
+            .strip_prefix("bytes=")
+            .unwrap()
+            .split_once('-')
+            .unwrap();
+        (start.parse().unwrap(), end.parse().unwrap())
+    }
+
+    #[test]
+    fn skip_done() {
+        let holes = vec![(0, 99), (MIN_CHUNK * 2, MIN_CHUNK * 3)];
+        let gen = HeadersGen::skipping(holes.clone(), 4);
+        let ranges: Vec<(usize, usize)> = std::iter::from_fn(|| gen.next())
+            .map(|(_, hm)| range(&hm))
+            .collect();
+        assert_eq!(ranges, holes);
+    }
+
+    #[test]
+    fn adapt() {
+        let total = 1 << 30;
+        let gen = HeadersGen::new(total, 4);
+        let (id, hm) = gen.next().unwrap();
+        assert_eq!(range(&hm), (0, FIRST_CHUNK - 1));
+        // 10MB/s, a range takes 40MB
+        gen.finish(id, 10_000_000, Duration::from_secs(1));
+        let (_, hm) = gen.next().unwrap();
+        assert_eq!(range(&hm), (FIRST_CHUNK, FIRST_CHUNK + 40_000_000 - 1));
+        // a small stream is shared by the workers
+        let gen = HeadersGen::new(1_500_000, 4);
+        let (_, hm) = gen.next().unwrap();
+        assert_eq!(range(&hm), (0, MIN_CHUNK - 1));
+    }
+
+    #[test]
+    fn steal() {
+        let gen = HeadersGen::new(MIN_CHUNK * 8, 1);
+        let (slow, hm) = gen.next().unwrap();
+        assert_eq!(range(&hm), (0, MIN_CHUNK * 8 - 1));
+        assert_eq!(gen.progress(slow, MIN_CHUNK * 2), Some(MIN_CHUNK * 8 - 1));
+        // an idle worker takes the back half of what's left
+        let (fast, hm) = gen.next().unwrap();
+        assert_eq!(range(&hm), (MIN_CHUNK * 5, MIN_CHUNK * 8 - 1));
+        assert_eq!(gen.progress(slow, MIN_CHUNK * 4), Some(MIN_CHUNK * 5 - 1));
+        assert_eq!(gen.progress(fast, MIN_CHUNK * 7), Some(MIN_CHUNK * 8 - 1));
+        // too little left to split
+        assert!(gen.next().is_none());
+    }
+
     #[test]
     fn test() {
-        let headers = std::sync::Arc::new(HeadersGen::new(0, 25_000_000_000_000));
+        let headers = std::sync::Arc::new(HeadersGen::new(25_000_000_000_000, 5));
         let mut jhs = Vec::new();
         let (tx, rx) = std::sync::mpsc::channel::<String>();
         let jh = std::thread::spawn(move || {
@@ -74,7 +236,7 @@ mod tests {
             let headers_c = headers.clone();
             let tx_c = tx.clone();
             let jh = std::thread::spawn(move || {
-                while let Some(hm) = headers_c.next() {
+                while let Some((_, hm)) = headers_c.next() {
                     let range = hm.get("Range").unwrap().to_str().unwrap();
                     println!("{}", range);
                     tx_c.send(range.to_string()).unwrap();
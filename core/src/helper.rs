@@ -1,8 +1,14 @@
 //! Helper funtions for bili_downlader
 
-use crate::config::FFMPEG;
+use crate::config::{HttpConfig, COOKIE, FFMPEG, USER_AGENT};
+use crate::error::Error;
+use crate::mirrors::{self, Mirrors};
+use crate::retry::RetryPolicy;
+use crate::store;
+use std::sync::Arc;
+use std::time::Duration;
 use tauri::api::path;
-use tokio::fs::{self, OpenOptions};
+use tokio::fs;
 use tokio::process::Command;
 
 /// As the name, create a tokio runtime at current thread.
@@ -13,25 +19,131 @@ pub fn create_rt() -> tokio::runtime::Runtime {
         .unwrap()
 }
 
-/// Retuen a BufWriter, create new, overwrite but **no** truncate
-pub async fn fs_open(path: &str) -> tokio::sync::RwLock<tokio::io::BufWriter<tokio::fs::File>> {
-    let file = OpenOptions::new()
+/// Open a file for `write_at`, create new, overwrite but **no** truncate
+pub fn fs_open(path: &str) -> std::io::Result<std::fs::File> {
+    std::fs::OpenOptions::new()
         .create(true)
         .write(true)
+        .truncate(false)
         .open(path)
+}
+
+/// Reserve `len` bytes for `file`, so that the disk doesn't fill up halfway.
+/// Where the file system can't allocate, the file only gets the length.
+/// Any other failure, such as a full disk, is returned
+pub(crate) fn preallocate(file: &std::fs::File, len: u64) -> std::io::Result<()> {
+    use fs2::FileExt;
+    match file.allocate(len) {
+        Ok(()) => return Ok(()),
+        Err(e) if !cannot_allocate(&e) => return Err(e),
+        Err(_) => {}
+    }
+    match file.metadata()?.len() < len {
+        true => file.set_len(len),
+        false => Ok(()),
+    }
+}
+
+/// The file system doesn't support allocating, unlike failing to
+#[cfg(unix)]
+fn cannot_allocate(e: &std::io::Error) -> bool {
+    e.raw_os_error()
+        .is_some_and(|code| [libc::EOPNOTSUPP, libc::ENOTSUP, libc::EINVAL].contains(&code))
+}
+
+#[cfg(windows)]
+fn cannot_allocate(e: &std::io::Error) -> bool {
+    e.kind() == std::io::ErrorKind::Unsupported
+}
+
+/// The disk is full
+#[cfg(unix)]
+pub(crate) fn disk_full(e: &std::io::Error) -> bool {
+    e.raw_os_error() == Some(libc::ENOSPC)
+}
+
+/// The disk is full, `ERROR_HANDLE_DISK_FULL` or `ERROR_DISK_FULL`
+#[cfg(windows)]
+pub(crate) fn disk_full(e: &std::io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(39 | 112))
+}
+
+/// Free bytes on the disk of `path`, or of its nearest existing parent
+pub(crate) fn available_space<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<u64> {
+    let mut path = path.as_ref();
+    while !path.exists() {
+        match path.parent() {
+            Some(parent) => path = parent,
+            None => break,
+        }
+    }
+    fs2::available_space(path)
+}
+
+/// Write `buf` at `offset` on the blocking pool.
+/// Positional, so that the range workers write concurrently without a lock
+pub(crate) async fn write_at(
+    file: Arc<std::fs::File>,
+    buf: Vec<u8>,
+    offset: u64,
+) -> std::io::Result<()> {
+    tokio::task::spawn_blocking(move || write_all_at(&file, &buf, offset))
         .await
-        .unwrap();
-    tokio::sync::RwLock::new(tokio::io::BufWriter::new(file))
+        .map_err(std::io::Error::other)?
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &std::fs::File, buf: &[u8], offset: u64) -> std::io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.write_all_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &std::fs::File, mut buf: &[u8], mut offset: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_write(buf, offset)? {
+            0 => return Err(std::io::ErrorKind::WriteZero.into()),
+            n => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
 }
 
 /// Create folders recursively
-pub async fn mkdir<P: AsRef<std::path::Path>>(path: P) {
-    fs::create_dir_all(path).await.unwrap();
+pub async fn mkdir<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<()> {
+    fs::create_dir_all(path).await
 }
 
-/// Remove the cache folder
-pub(crate) fn rm_cache<P: AsRef<std::path::Path>>(cache_path: P) {
-    std::fs::remove_dir_all(cache_path).unwrap();
+/// Remove the cache folder, it's fine if it's already gone
+pub(crate) fn rm_cache<P: AsRef<std::path::Path>>(cache_path: P) -> std::io::Result<()> {
+    match std::fs::remove_dir_all(cache_path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Where the app keeps its own files, such as the task journal
+/// Set to keep the task journal and the download archive somewhere else, such as in tests
+pub const DATA_DIR_ENV: &str = "BILI_DATA_DIR";
+
+/// Where the task journal and the download archive are kept, see `DATA_DIR_ENV`.
+/// The unit tests keep away from the ones of the app
+pub(crate) fn store_dir() -> std::path::PathBuf {
+    match std::env::var_os(DATA_DIR_ENV) {
+        Some(dir) => dir.into(),
+        None if cfg!(test) => std::env::temp_dir().join("bili_test_data"),
+        None => data_dir(),
+    }
+}
+
+pub fn data_dir() -> std::path::PathBuf {
+    let mut path = path::data_dir().unwrap_or_else(std::env::temp_dir);
+    path.push("bilibili downloader");
+    path
 }
 
 pub fn download_dir() -> std::path::PathBuf {
@@ -42,55 +154,205 @@ pub fn download_dir() -> std::path::PathBuf {
 
 /// Merge video and audio using this command:
 /// `ffmpeg -y -i video.mp4 -i audio.mp4 -c:v copy -c:a copy -o output.mp4`
+/// The audio input is skipped if `a_path` is `None`
 pub(crate) async fn merge(
     v_path: String,
-    a_path: String,
+    a_path: Option<String>,
     out_path: String,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> crate::Result<()> {
     let out_path = std::path::PathBuf::from(out_path);
-    let out_dir = out_path.parent().unwrap();
-    mkdir(out_dir).await;
-    let output = Command::new(FFMPEG.get().unwrap())
-        .arg("-y")
-        .arg("-i")
-        .arg(v_path)
-        .arg("-i")
-        .arg(a_path)
+    if let Some(out_dir) = out_path.parent() {
+        mkdir(out_dir).await?;
+    }
+    let mut cmd = Command::new(FFMPEG.get().map_or("ffmpeg", |f| f.as_str()));
+    cmd.arg("-y").arg("-i").arg(v_path);
+    if let Some(a_path) = a_path {
+        cmd.arg("-i").arg(a_path);
+    }
+    let output = cmd
         .arg("-c:v")
         .arg("copy")
         .arg("-c:a")
         .arg("copy")
         .arg(&out_path)
         .output()
-        .await?;
+        .await
+        .map_err(|e| Error::FfmpegFailed(format!("failed to run ffmpeg: {e}")))?;
 
     println!("status: {}", output.status);
-    println!("stdout: {:?}", std::str::from_utf8(&output.stdout));
-    println!("stderr: {:?}", std::str::from_utf8(&output.stderr));
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        // the last lines tell what went wrong, the rest is the banner
+        let tail: Vec<&str> = stderr.lines().rev().take(3).collect();
+        let tail: Vec<&str> = tail.into_iter().rev().collect();
+        return Err(Error::FfmpegFailed(format!(
+            "{}: {}",
+            output.status,
+            tail.join("\n")
+        )));
+    }
     Ok(())
 }
 
+/// A name left empty by the filter, such as `//` or only spaces
+const UNTITLED: &str = "untitled";
+
+/// A name safe for the file system, `UNTITLED` if nothing is left of it
 pub(crate) fn file_name_filter(file_name: &str) -> String {
-    assert!(!file_name.is_empty(), "file name is empty");
-    sanitize_filename::sanitize(file_name)
+    let name = sanitize_filename::sanitize(file_name);
+    match name.trim() {
+        "" => UNTITLED.to_owned(),
+        _ => name,
+    }
 }
 
-pub(crate) async fn get_resp(
+/// The client shared by the tasks of a `Downloader`, with keep-alive, gzip and the timeouts.
+/// Falls back to the default client if the TLS backend fails to initialize
+pub(crate) fn client(http: &HttpConfig) -> reqwest::Client {
+    let builder = reqwest::Client::builder()
+        .gzip(true)
+        .pool_max_idle_per_host(http.pool_size)
+        .tcp_keepalive(Duration::from_secs(60))
+        .connect_timeout(Duration::from_secs(http.connect_timeout));
+    let builder = if http.http2 {
+        builder.http2_adaptive_window(true)
+    } else {
+        builder.http1_only()
+    };
+    builder.build().unwrap_or_else(|e| {
+        println!("Failed to build the http client: {e}");
+        reqwest::Client::new()
+    })
+}
+
+/// Get the html of a page with the cookie in config
+pub(crate) async fn get_html(
     client: &reqwest::Client,
     target: &str,
+) -> Result<String, reqwest::Error> {
+    client
+        .get(target)
+        .header(
+            reqwest::header::COOKIE,
+            COOKIE.get().map_or("", String::as_str),
+        )
+        .header(reqwest::header::USER_AGENT, USER_AGENT)
+        .send()
+        .await?
+        .text()
+        .await
+}
+
+/// The common response of `api.bilibili.com`
+#[derive(serde::Deserialize, Debug)]
+pub(crate) struct ApiResponse<T> {
+    pub code: i64,
+    #[serde(default)]
+    pub message: String,
+    pub data: Option<T>,
+}
+
+impl<T> ApiResponse<T> {
+    pub fn data(self) -> crate::Result<T> {
+        match (self.code, self.data) {
+            (0, Some(data)) => Ok(data),
+            (code, _) => Err(Error::from_api(code, &self.message)),
+        }
+    }
+}
+
+/// Get a json api with the cookie in config
+pub(crate) async fn get_json<T: serde::de::DeserializeOwned>(
+    client: &reqwest::Client,
+    target: &str,
+) -> Result<T, reqwest::Error> {
+    client
+        .get(target)
+        .header(
+            reqwest::header::COOKIE,
+            COOKIE.get().map_or("", String::as_str),
+        )
+        .header(reqwest::header::USER_AGENT, USER_AGENT)
+        .header(reqwest::header::REFERER, "https://www.bilibili.com/")
+        .send()
+        .await?
+        .json()
+        .await
+}
+
+/// Request a range from the mirror in use, moving to the next mirror on repeated errors.
+/// Gives up after the attempts of `policy`, or with `Error::UrlExpired`
+/// once every mirror answered 403 or the deadline of the urls has passed.
+/// Return the index of the mirror that answered
+pub(crate) async fn get_resp(
+    client: &reqwest::Client,
+    mirrors: &Mirrors,
     headers: &reqwest::header::HeaderMap,
-) -> reqwest::Response {
+    policy: &RetryPolicy,
+) -> crate::Result<(usize, reqwest::Response)> {
+    let read_timeout = Duration::from_secs(crate::config::http().read_timeout);
+    let ranged = headers.contains_key(reqwest::header::RANGE);
+    let mut attempt = 0;
     loop {
-        match client
-            .get(target)
-            .headers(headers.clone())
-            .timeout(tokio::time::Duration::new(*crate::config::TIME_RETRY, 0))
-            .send()
-            .await
-        {
-            Ok(resp) => break resp,
-            Err(_) => tokio::time::sleep(tokio::time::Duration::new(2, 0)).await,
+        let (index, target) = mirrors.current();
+        if mirrors::expired(&target, store::now()) {
+            return Err(Error::UrlExpired);
+        }
+        // only until the response starts, the ranges take as long as their size
+        let sent = tokio::time::timeout(
+            read_timeout,
+            client.get(&target).headers(headers.clone()).send(),
+        )
+        .await
+        .map_err(|_| {
+            Error::Network(format!(
+                "no response from {} in {}s",
+                mirrors::host(&target),
+                read_timeout.as_secs()
+            ))
+        })
+        .and_then(|sent| sent.map_err(Error::from));
+        let error = match sent {
+            // the whole stream written at the offset of the range would corrupt the file
+            Ok(resp)
+                if ranged
+                    && resp.status().is_success()
+                    && resp.status() != reqwest::StatusCode::PARTIAL_CONTENT =>
+            {
+                mirrors.rotate(index);
+                Error::Network(format!("{} ignored the range", mirrors::host(&target)))
+            }
+            Ok(resp) if resp.status().is_success() => {
+                mirrors.succeed(index);
+                return Ok((index, resp));
+            }
+            Ok(resp) if matches!(resp.status().as_u16(), 403 | 410) => {
+                if mirrors.forbid(index) {
+                    return Err(Error::UrlExpired);
+                }
+                // unless another worker has moved on already
+                mirrors.rotate(index);
+                continue;
+            }
+            Ok(resp) => {
+                let status = resp.status();
+                // another mirror might have it
+                if !policy.retryable(status.as_u16()) && !mirrors.rotate(index) {
+                    return Err(Error::Network(format!(
+                        "{status} from {}",
+                        mirrors::host(&target)
+                    )));
+                }
+                Error::Network(format!("{status} from {}", mirrors::host(&target)))
+            }
+            Err(e) => e,
         };
+        attempt += 1;
+        if attempt > policy.max_attempts {
+            return Err(error);
+        }
+        mirrors.fail(index);
+        tokio::time::sleep(policy.delay(attempt)).await;
     }
 }
 
@@ -105,5 +367,26 @@ mod tests {
         assert_eq!(name, String::from("hi,你好."));
         let name = file_name_filter("讨厌工作日😭//星穹铁道MMD：青雀&我的悲伤是水做的");
         println!("{name}");
+        assert_eq!(file_name_filter(""), UNTITLED);
+        assert_eq!(file_name_filter("//?*"), UNTITLED);
+        assert_eq!(file_name_filter("  "), UNTITLED);
+    }
+
+    #[test]
+    fn allocate() {
+        let dir = std::env::temp_dir().join("bili_allocate_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(available_space(dir.join("cache_0")).unwrap() > 0);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("video.m4s");
+        let file = fs_open(path.to_str().unwrap()).unwrap();
+        preallocate(&file, 1_000_000).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 1_000_000);
+        // the bytes written are kept
+        write_all_at(&file, b"bili", 10).unwrap();
+        preallocate(&file, 1_000_000).unwrap();
+        let written = std::fs::read(&path).unwrap();
+        assert_eq!((written.len(), &written[10..14]), (1_000_000, &b"bili"[..]));
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
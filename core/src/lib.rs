@@ -1,9 +1,31 @@
+pub use error::{Error, Result};
+
+mod archive;
+pub mod async_downloader;
+pub mod bangumi;
+pub mod collection;
 pub mod config;
 pub mod downloader;
+pub mod error;
+pub mod events;
 mod executor;
+pub mod favorites;
 mod headers;
 pub mod helper;
+mod limiter;
+pub mod manifest;
 mod message;
-mod process;
+mod mirrors;
+pub mod pages;
+pub mod playinfo;
+pub mod process;
+pub mod quality;
+pub mod retry;
+mod scheduler;
+pub mod space;
 mod state;
+pub mod store;
+pub mod target;
 pub mod task;
+mod verify;
+mod wbi;
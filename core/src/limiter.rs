@@ -0,0 +1,182 @@
+//! Token bucket rate limiters for the download speed.
+//! Every range worker takes tokens for the bytes it got from both the global bucket
+//! of the executor and the one of its task
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    // bytes per second, 0 is unlimited
+    rate: AtomicU64,
+    bucket: Mutex<Bucket>,
+    // wakes the ones waiting when the rate changes
+    changed: Notify,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl RateLimiter {
+    /// `None` is unlimited
+    pub fn new(rate: Option<u64>) -> Self {
+        let rate = rate.unwrap_or(0);
+        Self {
+            rate: AtomicU64::new(rate),
+            bucket: Mutex::new(Bucket {
+                tokens: rate as f64,
+                last: Instant::now(),
+            }),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Takes effect at once, the ones waiting wait by the new rate or go on if it's lifted
+    pub fn set_rate(&self, rate: Option<u64>) {
+        {
+            let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+            let now = Instant::now();
+            bucket.tokens = match (self.rate(), rate) {
+                // the debt is forgiven
+                (_, None) => 0.0,
+                // full as a new one
+                (None, Some(rate)) => rate as f64,
+                // refilled by the old rate until now
+                (Some(old), Some(_)) => {
+                    bucket.tokens + now.duration_since(bucket.last).as_secs_f64() * old as f64
+                }
+            };
+            bucket.last = now;
+            self.rate.store(rate.unwrap_or(0), Ordering::Relaxed);
+        }
+        self.changed.notify_waiters();
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        match self.rate.load(Ordering::Relaxed) {
+            0 => None,
+            rate => Some(rate),
+        }
+    }
+
+    /// Take `n` tokens, wait until the bucket is refilled if it runs into debt.
+    /// At most one second of the rate is saved up for bursts
+    pub async fn acquire(&self, n: usize) {
+        let Some(rate) = self.rate() else {
+            return;
+        };
+        let rate = rate as f64;
+        // the tokens to be refilled before going on
+        let mut debt = {
+            let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+            let now = Instant::now();
+            let refilled = now.duration_since(bucket.last).as_secs_f64() * rate;
+            bucket.tokens = (bucket.tokens + refilled).min(rate) - n as f64;
+            bucket.last = now;
+            -bucket.tokens
+        };
+        loop {
+            // before reading the rate, not to miss a change
+            let changed = self.changed.notified();
+            let Some(rate) = self.rate() else {
+                return;
+            };
+            let rate = rate as f64;
+            if debt <= 0.0 {
+                return;
+            }
+            let now = Instant::now();
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs_f64(debt / rate)) => return,
+                _ = changed => debt -= now.elapsed().as_secs_f64() * rate,
+            }
+        }
+    }
+}
+
+/// The limiters a task is downloaded under
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Limits {
+    /// Shared by the tasks of an executor
+    pub global: Arc<RateLimiter>,
+    pub task: Arc<RateLimiter>,
+}
+
+impl Limits {
+    /// If any speed limit is set, the speed then says nothing about the network
+    pub fn limited(&self) -> bool {
+        self.global.rate().is_some() || self.task.rate().is_some()
+    }
+
+    pub async fn acquire(&self, n: usize) {
+        self.global.acquire(n).await;
+        self.task.acquire(n).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let limiter = RateLimiter::new(None);
+            let now = Instant::now();
+            limiter.acquire(usize::MAX / 2).await;
+            assert!(now.elapsed() < Duration::from_millis(50));
+            // a full bucket, then half a second of debt
+            let limiter = RateLimiter::new(Some(1_000_000));
+            let now = Instant::now();
+            limiter.acquire(1_000_000).await;
+            assert!(now.elapsed() < Duration::from_millis(50));
+            limiter.acquire(500_000).await;
+            let elapsed = now.elapsed();
+            assert!(elapsed >= Duration::from_millis(450), "{elapsed:?}");
+            assert!(elapsed < Duration::from_millis(900), "{elapsed:?}");
+            // a waiting one goes on when the limit is lifted
+            let limiter = Arc::new(limiter);
+            let waiting = tokio::spawn({
+                let limiter = limiter.clone();
+                async move { limiter.acquire(10_000_000).await }
+            });
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let now = Instant::now();
+            limiter.set_rate(None);
+            waiting.await.unwrap();
+            assert!(now.elapsed() < Duration::from_millis(50));
+            // and waits shorter when raised
+            limiter.set_rate(Some(1_000_000));
+            limiter.acquire(1_000_000).await;
+            let waiting = tokio::spawn({
+                let limiter = limiter.clone();
+                async move { limiter.acquire(1_000_000).await }
+            });
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let now = Instant::now();
+            limiter.set_rate(Some(100_000_000));
+            waiting.await.unwrap();
+            assert!(now.elapsed() < Duration::from_millis(50));
+            // lifted at runtime
+            limiter.set_rate(None);
+            let now = Instant::now();
+            limiter.acquire(10_000_000).await;
+            assert!(now.elapsed() < Duration::from_millis(50));
+        });
+    }
+}
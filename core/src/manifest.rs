@@ -0,0 +1,195 @@
+//! The sidecar manifest in `cache_{id}`,
+//! records what a task has written so that it could be resumed after a restart
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::error::Error;
+use crate::task::TaskOptions;
+
+type ManifestResult<T> = Result<T, Error>;
+
+const MANIFEST: &str = "manifest.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub id: usize,
+    pub target: String,
+    pub options: TaskOptions,
+    pub title: String,
+    /// The chosen stream, such as `1080P60 HEVC`
+    pub quality: String,
+    /// By the file name in the cache dir
+    pub streams: BTreeMap<String, Stream>,
+}
+
+/// A cached stream
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stream {
+    /// The url it was downloaded from last time, might have expired
+    pub url: String,
+    pub total: usize,
+    /// `(start, end)` of the chunks written, inclusive
+    pub chunks: Vec<(usize, usize)>,
+}
+
+impl Stream {
+    pub fn finished(&self) -> usize {
+        self.chunks.iter().map(|(start, end)| end - start + 1).sum()
+    }
+
+    /// `(start, end)` of the bytes no chunk covers, inclusive
+    pub fn holes(&self) -> Vec<(usize, usize)> {
+        let mut chunks = self.chunks.clone();
+        chunks.sort_unstable();
+        let mut holes = Vec::new();
+        let mut next = 0;
+        for (start, end) in chunks {
+            if start > next {
+                holes.push((next, start - 1));
+            }
+            next = next.max(end + 1);
+        }
+        if next < self.total {
+            holes.push((next, self.total - 1));
+        }
+        holes
+    }
+}
+
+impl Manifest {
+    pub fn total(&self) -> usize {
+        self.streams.values().map(|s| s.total).sum()
+    }
+
+    pub fn finished(&self) -> usize {
+        self.streams.values().map(Stream::finished).sum()
+    }
+
+    /// Start downloading `path` from `url`, return the chunks already written.
+    /// They're forgotten if the stream is not of the same length any more
+    pub(crate) fn open_stream(
+        &mut self,
+        path: &str,
+        url: &str,
+        total: usize,
+    ) -> Vec<(usize, usize)> {
+        let stream = self.streams.entry(key(path)).or_default();
+        if stream.total != total {
+            stream.total = total;
+            stream.chunks.clear();
+        }
+        stream.url = url.to_owned();
+        stream.chunks.clone()
+    }
+
+    pub(crate) fn record(&mut self, path: &str, chunk: (usize, usize)) {
+        if let Some(stream) = self.streams.get_mut(&key(path)) {
+            stream.chunks.push(chunk);
+        }
+    }
+
+    pub fn stream(&self, path: &str) -> Option<&Stream> {
+        self.streams.get(&key(path))
+    }
+
+    /// Drop the chunks of `path`, so that it's downloaded again
+    pub(crate) fn forget(&mut self, path: &str) {
+        if let Some(stream) = self.streams.get_mut(&key(path)) {
+            stream.chunks.clear();
+        }
+    }
+
+    pub(crate) fn load<P: AsRef<Path>>(cache_dir: P) -> ManifestResult<Self> {
+        let json = std::fs::read_to_string(cache_dir.as_ref().join(MANIFEST))?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    pub(crate) fn save<P: AsRef<Path>>(&self, cache_dir: P) -> ManifestResult<()> {
+        std::fs::create_dir_all(&cache_dir)?;
+        let json = serde_json::to_string(self)?;
+        Ok(std::fs::write(cache_dir.as_ref().join(MANIFEST), json)?)
+    }
+}
+
+fn key(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map_or_else(|| path.to_owned(), |f| f.to_string_lossy().into_owned())
+}
+
+pub(crate) fn cache_dir(save_dir: &str, id: usize) -> PathBuf {
+    Path::new(save_dir).join(format!("cache_{id}"))
+}
+
+/// The ids of the `cache_{id}` dirs in `save_dir`
+fn cache_ids(save_dir: &str) -> Vec<usize> {
+    let Ok(dir) = std::fs::read_dir(save_dir) else {
+        return Vec::new();
+    };
+    dir.filter_map(|entry| {
+        let name = entry.ok()?.file_name();
+        name.to_str()?.strip_prefix("cache_")?.parse().ok()
+    })
+    .collect()
+}
+
+/// The id after the ones left by the last run, so that they don't collide
+pub(crate) fn next_id(save_dir: &str) -> usize {
+    cache_ids(save_dir).into_iter().max().map_or(0, |id| id + 1)
+}
+
+/// The manifests of the unfinished tasks in `save_dir`, by id
+pub(crate) fn scan(save_dir: &str) -> Vec<Manifest> {
+    let mut manifests: Vec<Manifest> = cache_ids(save_dir)
+        .into_iter()
+        .filter_map(|id| Manifest::load(cache_dir(save_dir, id)).ok())
+        .collect();
+    manifests.sort_by_key(|m| m.id);
+    manifests
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_and_scan() {
+        let save_dir = std::env::temp_dir().join("bili_manifest_test");
+        let _ = std::fs::remove_dir_all(&save_dir);
+        let save_dir = save_dir.to_str().unwrap();
+        assert_eq!(next_id(save_dir), 0);
+        let mut manifest = Manifest {
+            id: 3,
+            target: "https://www.bilibili.com/video/BV1Ao4y1b7fj/".to_owned(),
+            ..Default::default()
+        };
+        let path = format!("{save_dir}/cache_3/title.mp4");
+        assert!(manifest.open_stream(&path, "https://v/1", 100).is_empty());
+        manifest.record(&path, (0, 49));
+        manifest.save(cache_dir(save_dir, 3)).unwrap();
+        // a cache dir without a manifest is skipped, but its id is taken
+        std::fs::create_dir_all(cache_dir(save_dir, 7)).unwrap();
+        assert_eq!(next_id(save_dir), 8);
+        let manifests = scan(save_dir);
+        assert_eq!(manifests, vec![manifest.clone()]);
+        assert_eq!((manifest.finished(), manifest.total()), (50, 100));
+        // the same stream with a new url keeps the chunks, another length drops them
+        assert_eq!(
+            manifest.open_stream(&path, "https://v/2", 100),
+            vec![(0, 49)]
+        );
+        assert!(manifest.open_stream(&path, "https://v/2", 120).is_empty());
+        manifest.record(&path, (10, 29));
+        manifest.record(&path, (60, 89));
+        manifest.record(&path, (20, 39));
+        assert_eq!(
+            manifest.stream(&path).unwrap().holes(),
+            vec![(0, 9), (40, 59), (90, 119)]
+        );
+        manifest.forget(&path);
+        assert_eq!(manifest.stream(&path).unwrap().holes(), vec![(0, 119)]);
+        std::fs::remove_dir_all(save_dir).unwrap();
+    }
+}
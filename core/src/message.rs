@@ -1,20 +1,48 @@
 //! The messages that would be send in channels
 
-use crate::task::Task;
+use crate::config::QueueConfig;
+use crate::error::Error;
+use crate::process::Progress;
+use crate::task::{RangeTiming, Task};
 
 // state req
 type PrcReq = (tokio::sync::oneshot::Sender<String>, usize);
+type PgReq = (tokio::sync::oneshot::Sender<Option<Progress>>, usize);
 type TtReq = (tokio::sync::oneshot::Sender<String>, usize);
+type QlReq = (tokio::sync::oneshot::Sender<String>, usize);
+type MrReq = (tokio::sync::oneshot::Sender<Vec<String>>, usize);
+type TmReq = (tokio::sync::oneshot::Sender<Vec<RangeTiming>>, usize);
+type ChReq = (tokio::sync::oneshot::Sender<Vec<usize>>, usize);
+type ErReq = (tokio::sync::oneshot::Sender<Option<Error>>, usize);
+// (parent id, title, children ids)
+type Group = (usize, String, Vec<usize>);
+// (global, per task) bytes per second
+type RateLimit = (Option<u64>, Option<u64>);
 type StReq = (tokio::sync::oneshot::Sender<usize>, usize);
 
 #[derive(Debug)]
 pub enum Message {
-    Job(Task),
+    Job(Box<Task>),
+    Group(Group),
     Process(PrcReq),
+    Progress(PgReq),
     State(StReq),
     Title(TtReq),
+    Quality(QlReq),
+    Mirrors(MrReq),
+    Timings(TmReq),
+    Children(ChReq),
+    Error(ErReq),
     Cancel(usize),
     Switch(usize),
+    Retry(usize),
+    RateLimit(RateLimit),
+    // (id, priority)
+    Priority((usize, i32)),
+    Top(usize),
+    QueueLimits(QueueConfig),
+    // sent by a task when its execution returns
+    Done(usize),
     SwitchAll,
     Terminate,
 }
@@ -0,0 +1,253 @@
+//! The mirrors of a stream, its `baseUrl` followed by the `backupUrl`s.
+//! The range workers of a stream share them and rotate to the next mirror
+//! when the current one keeps failing or is too slow.
+//! All of them are replaced when the signed urls expire
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Failures in a row before moving to the next mirror
+const MAX_FAILURES: usize = 3;
+/// In bytes per second, a stream downloaded slower moves to the next mirror
+const MIN_SPEED: f64 = 100_000.0;
+/// A mirror is only judged slow over at least this many bytes of the stream,
+/// so the last small ranges don't count
+const SLOW_BYTES: usize = 4 << 20;
+/// And at least this long
+const SLOW_WINDOW: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub(crate) struct Mirrors {
+    urls: RwLock<Vec<String>>,
+    current: AtomicUsize,
+    failures: AtomicUsize,
+    // a bit for each mirror that answered 403 since the urls were replaced
+    forbidden: AtomicU64,
+    // how many times the urls have been replaced
+    generation: AtomicUsize,
+    // `(since, bytes)` the stream got from the current mirror
+    window: Mutex<(Instant, usize)>,
+}
+
+/// `primary` first, the duplicated backups are dropped
+fn ordered(primary: String, backup: Vec<String>) -> Vec<String> {
+    let mut urls = vec![primary];
+    for url in backup {
+        if !urls.contains(&url) {
+            urls.push(url);
+        }
+    }
+    urls
+}
+
+impl Mirrors {
+    pub fn new(primary: String, backup: Vec<String>) -> Self {
+        Self {
+            urls: RwLock::new(ordered(primary, backup)),
+            current: AtomicUsize::new(0),
+            failures: AtomicUsize::new(0),
+            forbidden: AtomicU64::new(0),
+            generation: AtomicUsize::new(0),
+            window: Mutex::new((Instant::now(), 0)),
+        }
+    }
+
+    fn urls(&self) -> std::sync::RwLockReadGuard<'_, Vec<String>> {
+        self.urls.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn len(&self) -> usize {
+        self.urls().len()
+    }
+
+    pub fn primary(&self) -> String {
+        self.urls()[0].to_owned()
+    }
+
+    /// The index and the url of the mirror in use,
+    /// the index is passed back when reporting on it
+    pub fn current(&self) -> (usize, String) {
+        let urls = self.urls();
+        let index = self.current.load(Ordering::Relaxed) % urls.len();
+        (index, urls[index].to_owned())
+    }
+
+    /// Changed by `Mirrors::replace`,
+    /// so that the workers hitting the same expired urls refresh them only once
+    pub fn generation(&self) -> usize {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Use fresh urls of the same stream, starting from the primary one again
+    pub fn replace(&self, primary: String, backup: Vec<String>) {
+        let mut urls = self.urls.write().unwrap_or_else(|e| e.into_inner());
+        *urls = ordered(primary, backup);
+        self.current.store(0, Ordering::Relaxed);
+        self.failures.store(0, Ordering::Relaxed);
+        self.forbidden.store(0, Ordering::SeqCst);
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.restart_window();
+    }
+
+    fn restart_window(&self) {
+        *self.window.lock().unwrap_or_else(|e| e.into_inner()) = (Instant::now(), 0);
+    }
+
+    /// Count `bytes` of the stream from mirror `index` by any worker.
+    /// Once enough is counted, move to the next mirror if the stream got less than `MIN_SPEED`.
+    /// Return true if it moved
+    pub fn measure(&self, index: usize, bytes: usize) -> bool {
+        if self.current.load(Ordering::Relaxed) != index {
+            return false;
+        }
+        let speed = {
+            let mut window = self.window.lock().unwrap_or_else(|e| e.into_inner());
+            window.1 += bytes;
+            let elapsed = window.0.elapsed();
+            if elapsed < SLOW_WINDOW || window.1 < SLOW_BYTES {
+                return false;
+            }
+            let speed = window.1 as f64 / elapsed.as_secs_f64();
+            *window = (Instant::now(), 0);
+            speed
+        };
+        speed < MIN_SPEED && self.rotate(index)
+    }
+
+    /// Report a 403 from mirror `index`, return true once every mirror has answered 403
+    pub fn forbid(&self, index: usize) -> bool {
+        let len = self.len().min(64);
+        let all = u64::MAX >> (64 - len);
+        let forbidden = self
+            .forbidden
+            .fetch_or(1 << index.min(63), Ordering::SeqCst)
+            | 1 << index.min(63);
+        forbidden & all == all
+    }
+
+    /// Report a failed request to mirror `index`, return true if it moved to the next one.
+    /// Reports on a mirror not in use any more are ignored
+    pub fn fail(&self, index: usize) -> bool {
+        if self.current.load(Ordering::Relaxed) != index {
+            return false;
+        }
+        if self.failures.fetch_add(1, Ordering::Relaxed) + 1 < MAX_FAILURES {
+            return false;
+        }
+        self.rotate(index)
+    }
+
+    pub fn succeed(&self, index: usize) {
+        if self.current.load(Ordering::Relaxed) == index {
+            self.failures.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Move from mirror `index` to the next one right now, such as when it's too slow.
+    /// Return false if there is no other mirror or another worker has moved already
+    pub fn rotate(&self, index: usize) -> bool {
+        let urls = self.urls();
+        if urls.len() < 2 {
+            return false;
+        }
+        let next = (index + 1) % urls.len();
+        let rotated = self
+            .current
+            .compare_exchange(index, next, Ordering::SeqCst, Ordering::Relaxed)
+            .is_ok();
+        if rotated {
+            self.failures.store(0, Ordering::Relaxed);
+            self.restart_window();
+            println!("Switched to mirror {next}: {}", host(&urls[next]));
+        }
+        rotated
+    }
+}
+
+/// The host of a url, to show which mirror is in use
+pub(crate) fn host(url: &str) -> &str {
+    let url = url.split_once("://").map_or(url, |(_, rest)| rest);
+    url.split(['/', '?']).next().unwrap_or(url)
+}
+
+/// If the `deadline=` of a signed url has passed
+pub(crate) fn expired(url: &str, now: u64) -> bool {
+    url.split_once('?')
+        .into_iter()
+        .flat_map(|(_, query)| query.split('&'))
+        .filter_map(|pair| pair.strip_prefix("deadline="))
+        .filter_map(|deadline| deadline.parse::<u64>().ok())
+        .any(|deadline| deadline <= now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slow() {
+        let mirrors = Mirrors::new("https://a/x".to_owned(), vec!["https://b/x".to_owned()]);
+        // a lot in no time, not judged before the window
+        assert!(!mirrors.measure(0, SLOW_BYTES * 10));
+        // long enough but only the last few bytes
+        mirrors.restart_window();
+        mirrors.window.lock().unwrap().0 -= SLOW_WINDOW * 6;
+        assert!(!mirrors.measure(0, 1000));
+        // enough bytes too, but slow
+        assert!(mirrors.measure(0, SLOW_BYTES));
+        assert_eq!(mirrors.current().0, 1);
+        // fast enough
+        mirrors.window.lock().unwrap().0 -= SLOW_WINDOW;
+        assert!(!mirrors.measure(1, SLOW_BYTES * 10));
+        assert_eq!(mirrors.current().0, 1);
+    }
+
+    #[test]
+    fn forbid() {
+        let mirrors = Mirrors::new("https://a/x".to_owned(), vec!["https://b/x".to_owned()]);
+        assert!(!mirrors.forbid(0));
+        // again from a worker that lost the race to rotate
+        assert!(!mirrors.forbid(0));
+        assert!(mirrors.forbid(1));
+        mirrors.replace("https://c/x".to_owned(), vec![]);
+        assert!(mirrors.forbid(0));
+    }
+
+    #[test]
+    fn rotate() {
+        let mirrors = Mirrors::new(
+            "https://a.mcdn.bilivideo.cn:8082/v1/x.m4s?e=1".to_owned(),
+            vec!["https://b/x.m4s".to_owned(), "https://b/x.m4s".to_owned()],
+        );
+        assert_eq!(host(&mirrors.current().1), "a.mcdn.bilivideo.cn:8082");
+        assert!(!mirrors.fail(0));
+        mirrors.succeed(0);
+        assert!(!mirrors.fail(0));
+        assert!(!mirrors.fail(0));
+        assert!(mirrors.fail(0));
+        assert_eq!(mirrors.current(), (1, "https://b/x.m4s".to_owned()));
+        // another worker reporting on the old mirror
+        assert!(!mirrors.fail(0));
+        assert!(!mirrors.rotate(0));
+        assert!(mirrors.rotate(1));
+        assert_eq!(mirrors.current().0, 0);
+        let single = Mirrors::new("https://a/x".to_owned(), Vec::new());
+        assert!(!single.rotate(0));
+        assert_eq!(single.primary(), "https://a/x");
+        // fresh urls
+        let generation = mirrors.generation();
+        mirrors.replace("https://c/x".to_owned(), Vec::new());
+        assert_eq!(mirrors.current(), (0, "https://c/x".to_owned()));
+        assert_eq!(mirrors.generation(), generation + 1);
+    }
+
+    #[test]
+    fn deadline() {
+        let url = "https://a/x.m4s?e=ig8&deadline=1688362382&gen=playurlv2";
+        assert!(!expired(url, 1688362381));
+        assert!(expired(url, 1688362382));
+        assert!(!expired("https://a/x.m4s?e=1", u64::MAX));
+        assert!(!expired("https://a/x.m4s", u64::MAX));
+    }
+}
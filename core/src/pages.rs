@@ -0,0 +1,190 @@
+//! Multi-part (分P) videos, select the pages to download
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+use crate::helper;
+use crate::playinfo::{InitialState, Page, VideoData};
+
+/// The pages to download, parsed from `all` or a list like `1-5,8`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum Pages {
+    All,
+    /// Inclusive ranges
+    List(Vec<(u32, u32)>),
+}
+
+impl Pages {
+    pub fn contains(&self, page: u32) -> bool {
+        match self {
+            Pages::All => true,
+            Pages::List(ranges) => ranges.iter().any(|&(from, to)| from <= page && page <= to),
+        }
+    }
+
+    /// The selected pages, in order
+    pub fn select<'a>(&self, pages: &'a [Page]) -> Vec<&'a Page> {
+        pages.iter().filter(|p| self.contains(p.page)).collect()
+    }
+}
+
+impl FromStr for Pages {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() || s.eq_ignore_ascii_case("all") {
+            return Ok(Pages::All);
+        }
+        let page = |p: &str| {
+            p.trim()
+                .parse::<u32>()
+                .ok()
+                .filter(|&p| p > 0)
+                .ok_or_else(|| format!("invalid page `{p}` in `{s}`"))
+        };
+        let ranges = s
+            .split(',')
+            .map(|part| match part.split_once('-') {
+                Some((from, to)) => {
+                    let (from, to) = (page(from)?, page(to)?);
+                    match from <= to {
+                        true => Ok((from, to)),
+                        false => Err(format!("invalid range `{part}` in `{s}`")),
+                    }
+                }
+                None => page(part).map(|p| (p, p)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Pages::List(ranges))
+    }
+}
+
+impl fmt::Display for Pages {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pages::All => write!(f, "all"),
+            Pages::List(ranges) => {
+                let ranges: Vec<String> = ranges
+                    .iter()
+                    .map(|&(from, to)| match from == to {
+                        true => from.to_string(),
+                        false => format!("{from}-{to}"),
+                    })
+                    .collect();
+                write!(f, "{}", ranges.join(","))
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for Pages {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Pages> for String {
+    fn from(pages: Pages) -> Self {
+        pages.to_string()
+    }
+}
+
+/// Fetch the video data, including all pages, of the video `target` points to
+pub(crate) async fn video_data(client: &reqwest::Client, target: &str) -> crate::Result<VideoData> {
+    let html = helper::get_html(client, target).await?;
+    Ok(InitialState::from_html(&html)?.video_data)
+}
+
+/// Videos of a list whose pages are fetched at a time
+const LIST_CONCURRENCY: usize = 4;
+
+/// The pages `selected` of each video of a list, with the number of its pages.
+/// `None` for a video of one page, or one failed to be listed which is downloaded as one.
+/// `videos` are `(bvid, the number of pages if the list tells it)`,
+/// the pages are only fetched for the ones possibly multi-part
+pub(crate) async fn select_each(
+    client: &reqwest::Client,
+    videos: &[(String, Option<u32>)],
+    selected: &Pages,
+) -> Vec<Option<(Vec<Page>, usize)>> {
+    let permits = Arc::new(Semaphore::new(LIST_CONCURRENCY));
+    let mut handles = JoinSet::new();
+    for (i, (bvid, count)) in videos.iter().enumerate() {
+        if count.is_some_and(|count| count <= 1) {
+            continue;
+        }
+        let (client, permits, selected) = (client.clone(), permits.clone(), selected.clone());
+        let url = page_url(bvid, 1);
+        handles.spawn(async move {
+            let _permit = permits.acquire_owned().await;
+            let pages = match video_data(&client, &url).await {
+                Ok(data) if data.pages.len() > 1 => {
+                    let chosen = selected.select(&data.pages).into_iter().cloned().collect();
+                    Some((chosen, data.pages.len()))
+                }
+                Ok(_) => None,
+                Err(e) => {
+                    println!("Failed to list the pages of {url}: {e}");
+                    None
+                }
+            };
+            (i, pages)
+        });
+    }
+    let mut each = vec![None; videos.len()];
+    while let Some(res) = handles.join_next().await {
+        if let Ok((i, pages)) = res {
+            each[i] = pages;
+        }
+    }
+    each
+}
+
+/// The url of one page of a video
+pub(crate) fn page_url(bvid: &str, page: u32) -> String {
+    format!("https://www.bilibili.com/video/{bvid}/?p={page}")
+}
+
+/// The file name of one page, such as `title - P02 part`
+pub(crate) fn page_title(title: &str, page: &Page, total: usize) -> String {
+    let width = total.to_string().len();
+    format!("{title} - P{:0width$} {}", page.page, page.part)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_pages() {
+        assert_eq!("all".parse::<Pages>().unwrap(), Pages::All);
+        let pages: Pages = "1-5, 8".parse().unwrap();
+        assert_eq!(pages, Pages::List(vec![(1, 5), (8, 8)]));
+        assert!(pages.contains(3));
+        assert!(!pages.contains(6));
+        assert!(pages.contains(8));
+        assert_eq!(pages.to_string(), "1-5,8");
+        assert!("0".parse::<Pages>().is_err());
+        assert!("5-1".parse::<Pages>().is_err());
+        assert!("1-x".parse::<Pages>().is_err());
+    }
+
+    #[test]
+    fn name_page() {
+        let page = Page {
+            page: 2,
+            part: "intro".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(page_title("title", &page, 12), "title - P02 intro");
+        assert_eq!(page_title("title", &page, 3), "title - P2 intro");
+    }
+}
@@ -0,0 +1,316 @@
+//! Typed model of the json blobs embedded in a video page,
+//! `window.__playinfo__` (the same shape as the `playurl` api) and `window.__INITIAL_STATE__`
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use crate::error::Error;
+use crate::helper;
+use crate::pages;
+use crate::quality::{QualityPolicy, Selection};
+
+type ParseResult<T> = Result<T, Error>;
+
+const PLAYINFO_MARK: &str = "window.__playinfo__=";
+const INITIAL_STATE_MARK: &str = "window.__INITIAL_STATE__=";
+
+/// The response of `window.__playinfo__` or `x/player/playurl`
+#[derive(Deserialize, Debug, Clone)]
+pub struct PlayInfo {
+    pub code: i64,
+    #[serde(default)]
+    pub message: String,
+    pub data: Option<PlayUrl>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct PlayUrl {
+    #[serde(default)]
+    pub quality: u32,
+    #[serde(default)]
+    pub timelength: u64,
+    #[serde(default)]
+    pub accept_quality: Vec<u32>,
+    #[serde(default)]
+    pub accept_description: Vec<String>,
+    pub dash: Option<Dash>,
+    pub durl: Option<Vec<Durl>>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Dash {
+    #[serde(default)]
+    pub duration: u64,
+    #[serde(default)]
+    pub video: Vec<DashStream>,
+    /// `null` for silent videos
+    #[serde(default)]
+    pub audio: Option<Vec<DashStream>>,
+}
+
+/// One entry of `dash.video[]` or `dash.audio[]`
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct DashStream {
+    /// The quality id, e.g. 80 for 1080P, 30280 for 192K audio
+    pub id: u32,
+    #[serde(rename = "baseUrl")]
+    pub base_url: String,
+    #[serde(rename = "backupUrl", default)]
+    pub backup_url: Option<Vec<String>>,
+    #[serde(default)]
+    pub bandwidth: u64,
+    #[serde(rename = "mimeType", default)]
+    pub mime_type: String,
+    #[serde(default)]
+    pub codecs: String,
+    #[serde(default)]
+    pub codecid: u32,
+    #[serde(default)]
+    pub width: u32,
+    #[serde(default)]
+    pub height: u32,
+    #[serde(rename = "frameRate", default)]
+    pub frame_rate: String,
+}
+
+/// One segment of the legacy flv/mp4 format, used when no `dash` is offered
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Durl {
+    #[serde(default)]
+    pub order: u32,
+    #[serde(default)]
+    pub length: u64,
+    #[serde(default)]
+    pub size: u64,
+    pub url: String,
+    #[serde(default)]
+    pub backup_url: Option<Vec<String>>,
+}
+
+/// `window.__INITIAL_STATE__` of a `/video/BV…` page
+#[derive(Deserialize, Debug, Clone)]
+pub struct InitialState {
+    #[serde(rename = "videoData")]
+    pub video_data: VideoData,
+    /// The page the url points to, starts from 1
+    #[serde(default = "first_page")]
+    pub p: u32,
+}
+
+fn first_page() -> u32 {
+    1
+}
+
+impl InitialState {
+    pub fn from_html(html: &str) -> ParseResult<Self> {
+        extract_json(html, INITIAL_STATE_MARK)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct VideoData {
+    pub bvid: String,
+    #[serde(default)]
+    pub aid: u64,
+    #[serde(default)]
+    pub cid: u64,
+    pub title: String,
+    #[serde(default)]
+    pub pubdate: i64,
+    #[serde(default)]
+    pub duration: u64,
+    #[serde(default)]
+    pub owner: Owner,
+    #[serde(default)]
+    pub pages: Vec<Page>,
+    /// The collection (合集) this video belongs to
+    pub ugc_season: Option<UgcSeason>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Owner {
+    #[serde(default)]
+    pub mid: u64,
+    #[serde(default)]
+    pub name: String,
+}
+
+/// One part (分P) of a video
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Page {
+    pub cid: u64,
+    pub page: u32,
+    #[serde(default)]
+    pub part: String,
+    #[serde(default)]
+    pub duration: u64,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct UgcSeason {
+    pub id: u64,
+    pub title: String,
+    #[serde(default)]
+    pub sections: Vec<Section>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Section {
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub episodes: Vec<UgcEpisode>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct UgcEpisode {
+    #[serde(default)]
+    pub aid: u64,
+    pub bvid: String,
+    pub title: String,
+    /// The first page of the video
+    pub page: Option<Page>,
+}
+
+/// What `Task::parse` found in a page
+#[derive(Debug, Clone)]
+pub struct ParsedMedia {
+    /// The sanitized title, ready to be used as a file name.
+    /// Includes the page number and page title for multi-part videos
+    pub title: String,
+    /// The page (分P) the streams belong to
+    pub page: u32,
+    pub video_data: VideoData,
+    pub play_url: PlayUrl,
+}
+
+impl ParsedMedia {
+    /// Parse the html of a video page
+    pub fn from_html(html: &str) -> ParseResult<Self> {
+        let play_info: PlayInfo = extract_json(html, PLAYINFO_MARK)?;
+        let state = InitialState::from_html(html)?;
+        let play_url = play_info
+            .data
+            .ok_or_else(|| Error::from_api(play_info.code, &play_info.message))?;
+        let pages = &state.video_data.pages;
+        let title = match pages.iter().find(|p| p.page == state.p) {
+            Some(page) if pages.len() > 1 => {
+                pages::page_title(&state.video_data.title, page, pages.len())
+            }
+            _ => state.video_data.title.clone(),
+        };
+        Ok(Self {
+            title: helper::file_name_filter(&title),
+            page: state.p,
+            video_data: state.video_data,
+            play_url,
+        })
+    }
+
+    /// The cid of the page the streams belong to
+    pub fn cid(&self) -> u64 {
+        self.video_data
+            .pages
+            .iter()
+            .find(|p| p.page == self.page)
+            .map_or(self.video_data.cid, |p| p.cid)
+    }
+
+    /// The best video stream, highest quality first, then the highest bandwidth
+    pub fn video(&self) -> Option<&DashStream> {
+        self.play_url
+            .dash
+            .as_ref()?
+            .video
+            .iter()
+            .max_by_key(|s| (s.id, s.bandwidth))
+    }
+
+    /// The best audio stream
+    pub fn audio(&self) -> Option<&DashStream> {
+        self.play_url
+            .dash
+            .as_ref()?
+            .audio
+            .as_ref()?
+            .iter()
+            .max_by_key(|s| s.bandwidth)
+    }
+
+    /// Choose the streams to download by `policy`
+    pub fn select(&self, policy: &QualityPolicy) -> Option<Selection> {
+        policy.select(&self.play_url)
+    }
+}
+
+/// Deserialize the first json value right after `mark`
+fn extract_json<T: DeserializeOwned>(html: &str, mark: &str) -> ParseResult<T> {
+    let start = html
+        .find(mark)
+        .ok_or_else(|| Error::ParseFailed(format!("`{mark}` not found")))?
+        + mark.len();
+    let mut stream = serde_json::Deserializer::from_str(&html[start..]).into_iter::<T>();
+    match stream.next() {
+        Some(value) => Ok(value?),
+        None => Err(Error::ParseFailed(format!("nothing after `{mark}`"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HTML: &str = r#"<script>window.__playinfo__={"code":0,"message":"0","data":{"quality":80,"accept_quality":[80,64],"accept_description":["高清 1080P","高清 720P"],"dash":{"duration":60,"video":[{"id":64,"baseUrl":"https://v/64-avc","base_url":"https://v/64-avc","backupUrl":["https://b/64-avc"],"bandwidth":100,"codecs":"avc1.64001F","codecid":7,"width":1280,"height":720,"frameRate":"30"},{"id":80,"baseUrl":"https://v/80-hevc","bandwidth":150,"codecs":"hev1.1.6.L120.90","codecid":12,"width":1920,"height":1080,"frameRate":"30"},{"id":80,"baseUrl":"https://v/80-avc","backupUrl":["https://b1/80-avc","https://b2/80-avc"],"backup_url":["https://b1/80-avc","https://b2/80-avc"],"bandwidth":200,"codecs":"avc1.640032","codecid":7,"width":1920,"height":1080,"frameRate":"30"}],"audio":[{"id":30216,"baseUrl":"https://a/64k","bandwidth":64},{"id":30280,"baseUrl":"https://a/192k","bandwidth":192}]}}}</script><script>window.__INITIAL_STATE__={"p":1,"videoData":{"bvid":"BV1Ao4y1b7fj","aid":1,"cid":2,"title":"a/b:c","pages":[{"cid":2,"page":1,"part":"P1"}]}};(function(){var s;}());</script>"#;
+
+    #[test]
+    fn parse_html() {
+        let media = ParsedMedia::from_html(HTML).unwrap();
+        assert_eq!(media.title, "abc");
+        assert_eq!(media.video_data.bvid, "BV1Ao4y1b7fj");
+        assert_eq!(media.video_data.pages.len(), 1);
+        assert_eq!(media.video().unwrap().base_url, "https://v/80-avc");
+        assert_eq!(media.audio().unwrap().base_url, "https://a/192k");
+        let sel = media.select(&QualityPolicy::default()).unwrap();
+        assert_eq!(sel.video_url, "https://v/80-avc");
+        assert_eq!(
+            sel.video_backup,
+            vec!["https://b1/80-avc", "https://b2/80-avc"]
+        );
+        assert_eq!(sel.audio_url.as_deref(), Some("https://a/192k"));
+        assert_eq!(sel.to_string(), "1080P AVC");
+    }
+
+    #[test]
+    fn parse_multi_page() {
+        let html = HTML.replace(r#""p":1,"#, r#""p":2,"#).replace(
+            r#""pages":[{"cid":2,"page":1,"part":"P1"}]"#,
+            r#""pages":[{"cid":2,"page":1,"part":"P1"},{"cid":3,"page":2,"part":"outro"}]"#,
+        );
+        let media = ParsedMedia::from_html(&html).unwrap();
+        assert_eq!(media.page, 2);
+        assert_eq!(media.title, "abc - P2 outro");
+    }
+
+    #[test]
+    fn parse_durl() {
+        let html = r#"window.__playinfo__={"code":0,"data":{"quality":16,"durl":[{"order":1,"size":10,"url":"https://d/1","backup_url":["https://d/2"]}]}}</script>window.__INITIAL_STATE__={"videoData":{"bvid":"BV1","title":"t"}};"#;
+        let media = ParsedMedia::from_html(html).unwrap();
+        assert!(media.video().is_none());
+        let sel = media.select(&QualityPolicy::default()).unwrap();
+        assert_eq!(sel.video_url, "https://d/1");
+        assert_eq!(sel.video_backup, vec!["https://d/2"]);
+        assert_eq!(sel.audio_url, None);
+    }
+
+    #[test]
+    fn parse_segmented_durl() {
+        let html = r#"window.__playinfo__={"code":0,"data":{"quality":16,"durl":[{"order":1,"size":10,"url":"https://d/1"},{"order":2,"size":10,"url":"https://d/2"}]}}</script>window.__INITIAL_STATE__={"videoData":{"bvid":"BV1","title":"t"}};"#;
+        let media = ParsedMedia::from_html(html).unwrap();
+        assert!(media.select(&QualityPolicy::default()).is_none());
+    }
+
+    #[test]
+    fn missing_playinfo() {
+        assert!(ParsedMedia::from_html("<html></html>").is_err());
+    }
+}
@@ -1,12 +1,139 @@
 //! This is a helper for Task
-//! to record the process of downloading task
+//! to record the process of downloading task.
+//! The speed is measured over a sliding window of samples, see `Downloader::progress`
 
-use std::sync::atomic::{AtomicUsize, Ordering};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::task::RangeTiming;
+
+/// The timings kept, the older ones are dropped
+const MAX_TIMINGS: usize = 1000;
+/// Between the samples of the downloaded bytes
+const SAMPLE_EVERY: Duration = Duration::from_millis(200);
+/// Of the instantaneous speed
+const SHORT_WINDOW: Duration = Duration::from_secs(1);
+/// Of the smoothed speed, and of the samples kept
+const LONG_WINDOW: Duration = Duration::from_secs(10);
+
+/// What a task is doing
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    /// Parsing the target for the streams, also before starting
+    Resolving,
+    Downloading,
+    /// Merging the streams with ffmpeg and checking the merged file
+    Merging,
+    Done,
+}
+
+impl Phase {
+    fn from_u8(phase: u8) -> Self {
+        match phase {
+            1 => Phase::Downloading,
+            2 => Phase::Merging,
+            3 => Phase::Done,
+            _ => Phase::Resolving,
+        }
+    }
+}
+
+/// The bytes of a stream
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct StreamProgress {
+    /// `video` or `audio`
+    pub name: String,
+    pub downloaded: usize,
+    pub total: usize,
+}
+
+/// A snapshot of a task, returned by `Downloader::progress`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Progress {
+    pub phase: Phase,
+    pub downloaded: usize,
+    pub total: usize,
+    pub streams: Vec<StreamProgress>,
+    /// In bytes per second, over the last second
+    pub speed: f64,
+    /// In bytes per second, over the last 10 seconds
+    pub smoothed_speed: f64,
+    /// In seconds at the smoothed speed, `None` while nothing arrives
+    pub eta: Option<u64>,
+    /// The ranges being downloaded
+    pub connections: usize,
+    /// The requests made again after a range broke off
+    pub retries: usize,
+}
+
+impl Progress {
+    /// Of a group, summed over its children.
+    /// The phase is the earliest one a child is still in, the streams are left out
+    pub(crate) fn sum(children: &[Progress]) -> Progress {
+        let phase = [Phase::Downloading, Phase::Merging, Phase::Resolving]
+            .into_iter()
+            .find(|phase| children.iter().any(|c| c.phase == *phase))
+            .unwrap_or(Phase::Done);
+        let downloaded = children.iter().map(|c| c.downloaded).sum();
+        let total: usize = children.iter().map(|c| c.total).sum();
+        let smoothed_speed = children.iter().map(|c| c.smoothed_speed).sum();
+        Progress {
+            phase,
+            downloaded,
+            total,
+            streams: Vec::new(),
+            speed: children.iter().map(|c| c.speed).sum(),
+            smoothed_speed,
+            eta: eta(total.saturating_sub(downloaded), smoothed_speed),
+            connections: children.iter().map(|c| c.connections).sum(),
+            retries: children.iter().map(|c| c.retries).sum(),
+        }
+    }
+}
+
+/// In seconds, `None` while nothing arrives
+fn eta(remaining: usize, speed: f64) -> Option<u64> {
+    (speed > 0.0).then(|| (remaining as f64 / speed).ceil() as u64)
+}
+
+/// Counts the bytes of a stream, shared by its range workers
+#[derive(Debug)]
+pub(crate) struct StreamCount {
+    name: String,
+    total: usize,
+    finished: AtomicUsize,
+}
+
+/// A range being downloaded, counted until dropped
+pub(crate) struct Connection<'a>(&'a Process);
+
+impl Drop for Connection<'_> {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
 
 #[derive(Debug)]
 pub struct Process {
     pub total: AtomicUsize,
     pub finished: AtomicUsize,
+    streams: Mutex<Vec<Arc<StreamCount>>>,
+    // `(when, finished)`, the oldest first
+    samples: Mutex<VecDeque<(Instant, usize)>>,
+    connections: AtomicUsize,
+    retries: AtomicUsize,
+    phase: AtomicU8,
+    timings: Mutex<VecDeque<RangeTiming>>,
+}
+
+impl Default for Process {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Process {
@@ -14,15 +141,98 @@ impl Process {
         Process {
             total: AtomicUsize::new(0),
             finished: AtomicUsize::new(0),
+            streams: Mutex::new(Vec::new()),
+            samples: Mutex::new(VecDeque::new()),
+            connections: AtomicUsize::new(0),
+            retries: AtomicUsize::new(0),
+            phase: AtomicU8::new(0),
+            timings: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Count a stream of `total` bytes with `finished` of them cached
+    pub(crate) fn add_stream(&self, name: &str, total: usize, finished: usize) -> Arc<StreamCount> {
+        let stream = Arc::new(StreamCount {
+            name: name.to_owned(),
+            total,
+            finished: AtomicUsize::new(finished),
+        });
+        self.total.fetch_add(total, Ordering::SeqCst);
+        self.finished.fetch_add(finished, Ordering::SeqCst);
+        self.streams
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(stream.clone());
+        stream
+    }
+
+    /// `val` bytes of `stream` have arrived
+    pub(crate) fn add_finished(&self, stream: &StreamCount, val: usize) {
+        stream.finished.fetch_add(val, Ordering::SeqCst);
+        let finished = self.finished.fetch_add(val, Ordering::SeqCst) + val;
+        let mut samples = self.samples.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        if samples
+            .back()
+            .is_none_or(|(last, _)| now - *last >= SAMPLE_EVERY)
+        {
+            samples.push_back((now, finished));
+        }
+        while samples
+            .front()
+            .is_some_and(|(first, _)| now - *first > LONG_WINDOW)
+        {
+            samples.pop_front();
         }
     }
 
-    pub fn add_total(&self, val: usize) {
-        self.total.fetch_add(val, Ordering::SeqCst);
+    /// Count a range being downloaded until the returned guard is dropped
+    pub(crate) fn connect(&self) -> Connection<'_> {
+        self.connections.fetch_add(1, Ordering::SeqCst);
+        Connection(self)
+    }
+
+    pub(crate) fn add_retry(&self) {
+        self.retries.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn set_phase(&self, phase: Phase) {
+        self.phase.store(phase as u8, Ordering::SeqCst);
+    }
+
+    /// Start counting again, such as on retrying
+    pub fn reset(&self) {
+        self.total.store(0, Ordering::SeqCst);
+        self.finished.store(0, Ordering::SeqCst);
+        self.retries.store(0, Ordering::SeqCst);
+        self.set_phase(Phase::Resolving);
+        self.streams
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        self.samples
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        self.timings
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
     }
 
-    pub fn add_finished(&self, val: usize) {
-        self.finished.fetch_add(val, Ordering::SeqCst);
+    /// Keep how a range went
+    pub fn add_timing(&self, timing: RangeTiming) {
+        let mut timings = self.timings.lock().unwrap_or_else(|e| e.into_inner());
+        if timings.len() >= MAX_TIMINGS {
+            timings.pop_front();
+        }
+        timings.push_back(timing);
+    }
+
+    /// The finished ranges, the latest last
+    pub fn timings(&self) -> Vec<RangeTiming> {
+        let timings = self.timings.lock().unwrap_or_else(|e| e.into_inner());
+        timings.iter().cloned().collect()
     }
 
     pub fn total(&self) -> usize {
@@ -33,9 +243,93 @@ impl Process {
         self.finished.load(Ordering::SeqCst)
     }
 
+    /// In bytes per second, from the oldest sample within `window` to now
+    fn speed(&self, window: Duration, now: Instant) -> f64 {
+        let samples = self.samples.lock().unwrap_or_else(|e| e.into_inner());
+        let Some((since, base)) = samples.iter().find(|(when, _)| now - *when <= window) else {
+            return 0.0;
+        };
+        let elapsed = (now - *since).max(SAMPLE_EVERY).as_secs_f64();
+        self.finished().saturating_sub(*base) as f64 / elapsed
+    }
+
+    pub fn progress(&self) -> Progress {
+        let now = Instant::now();
+        let (downloaded, total) = (self.finished(), self.total());
+        let smoothed_speed = self.speed(LONG_WINDOW, now);
+        let streams = self.streams.lock().unwrap_or_else(|e| e.into_inner());
+        Progress {
+            phase: Phase::from_u8(self.phase.load(Ordering::SeqCst)),
+            downloaded,
+            total,
+            streams: streams
+                .iter()
+                .map(|s| StreamProgress {
+                    name: s.name.to_owned(),
+                    downloaded: s.finished.load(Ordering::SeqCst),
+                    total: s.total,
+                })
+                .collect(),
+            speed: self.speed(SHORT_WINDOW, now),
+            smoothed_speed,
+            eta: eta(total.saturating_sub(downloaded), smoothed_speed),
+            connections: self.connections.load(Ordering::SeqCst),
+            retries: self.retries.load(Ordering::SeqCst),
+        }
+    }
+
     pub fn get(&self) -> String {
         let finished = self.finished() as f64 / 1000000.;
         let total = self.total() as f64 / 1000000.;
         format!("{:.2}Mb / {:.2}Mb", finished, total)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress() {
+        let process = Process::new();
+        let video = process.add_stream("video", 10_000_000, 4_000_000);
+        let audio = process.add_stream("audio", 1_000_000, 0);
+        let progress = process.progress();
+        // the cached bytes are not speed
+        assert_eq!(
+            (progress.downloaded, progress.total),
+            (4_000_000, 11_000_000)
+        );
+        assert_eq!((progress.speed, progress.eta), (0.0, None));
+        let connection = process.connect();
+        for _ in 0..6 {
+            process.add_finished(&video, 100_000);
+            process.add_finished(&audio, 10_000);
+            std::thread::sleep(SAMPLE_EVERY);
+        }
+        process.add_retry();
+        process.set_phase(Phase::Downloading);
+        let progress = process.progress();
+        // 110KB every 200ms
+        assert!(
+            (400_000.0..600_000.0).contains(&progress.smoothed_speed),
+            "{progress:?}"
+        );
+        assert!(progress.eta.is_some_and(|eta| eta > 10), "{progress:?}");
+        assert_eq!(progress.streams[0].downloaded, 4_600_000);
+        assert_eq!(progress.streams[1].downloaded, 60_000);
+        assert_eq!((progress.connections, progress.retries), (1, 1));
+        assert_eq!(
+            serde_json::to_value(progress.phase).unwrap(),
+            serde_json::json!("downloading")
+        );
+        let group = Progress::sum(&[progress.clone(), Process::new().progress()]);
+        assert_eq!(group.phase, Phase::Downloading);
+        assert_eq!((group.downloaded, group.total), (4_660_000, 11_000_000));
+        assert_eq!(group.eta, progress.eta);
+        drop(connection);
+        process.reset();
+        assert_eq!(process.progress().connections, 0);
+        assert!(process.progress().streams.is_empty());
+    }
+}
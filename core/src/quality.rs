@@ -0,0 +1,292 @@
+//! Quality and codec selection policy for DASH streams
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::playinfo::{DashStream, PlayUrl};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Avc,
+    Hevc,
+    Av1,
+}
+
+impl Codec {
+    /// `codecid` in playinfo: 7 avc, 12 hevc, 13 av1
+    pub fn from_id(codecid: u32) -> Option<Self> {
+        match codecid {
+            7 => Some(Codec::Avc),
+            12 => Some(Codec::Hevc),
+            13 => Some(Codec::Av1),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Codec::Avc => write!(f, "AVC"),
+            Codec::Hevc => write!(f, "HEVC"),
+            Codec::Av1 => write!(f, "AV1"),
+        }
+    }
+}
+
+/// What to do if the preferred quality is not offered
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Fallback {
+    /// The nearest lower quality, or the nearest higher one if there is no lower
+    #[default]
+    Lower,
+    /// The nearest higher quality, or the nearest lower one if there is no higher
+    Higher,
+    /// Fail the task
+    Fail,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct QualityPolicy {
+    /// Preferred quality id, e.g. 80 for 1080P, 116 for 1080P60, 120 for 4K.
+    /// `None` means the best available
+    pub quality: Option<u32>,
+    /// Preferred codecs, in order. Codecs not listed come last
+    pub codecs: Vec<Codec>,
+    pub max_height: Option<u32>,
+    /// In bits per second
+    pub max_bandwidth: Option<u64>,
+    pub fallback: Fallback,
+}
+
+impl Default for QualityPolicy {
+    fn default() -> Self {
+        Self {
+            quality: None,
+            codecs: vec![Codec::Avc, Codec::Hevc, Codec::Av1],
+            max_height: None,
+            max_bandwidth: None,
+            fallback: Fallback::Lower,
+        }
+    }
+}
+
+/// The streams chosen by a `QualityPolicy`
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Selection {
+    pub video_url: String,
+    /// The mirrors of the video, tried in order after `video_url`
+    pub video_backup: Vec<String>,
+    /// `None` if the video is silent or only offered as `durl`
+    pub audio_url: Option<String>,
+    pub audio_backup: Vec<String>,
+    pub quality: u32,
+    pub codec: Option<Codec>,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl fmt::Display for Selection {
+    /// Such as `1080P60 HEVC`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", quality_name(self.quality))?;
+        if let Some(codec) = self.codec {
+            write!(f, " {codec}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The display name of a quality id
+pub fn quality_name(quality: u32) -> String {
+    match quality {
+        6 => "240P",
+        16 => "360P",
+        32 => "480P",
+        64 => "720P",
+        74 => "720P60",
+        80 => "1080P",
+        112 => "1080P+",
+        116 => "1080P60",
+        120 => "4K",
+        125 => "HDR",
+        126 => "Dolby Vision",
+        127 => "8K",
+        _ => return format!("Q{quality}"),
+    }
+    .to_owned()
+}
+
+impl QualityPolicy {
+    /// Choose the video and audio streams of a `PlayUrl`.
+    /// Return `None` if nothing could be chosen, or the video is split into several `durl` segments
+    pub fn select(&self, play_url: &PlayUrl) -> Option<Selection> {
+        let dash = match &play_url.dash {
+            Some(dash) if !dash.video.is_empty() => dash,
+            // legacy format, a video split into several segments is not supported
+            _ => {
+                let durl = match play_url.durl.as_deref()? {
+                    [durl] => durl,
+                    _ => return None,
+                };
+                return Some(Selection {
+                    video_url: durl.url.clone(),
+                    video_backup: durl.backup_url.clone().unwrap_or_default(),
+                    audio_url: None,
+                    audio_backup: Vec::new(),
+                    quality: play_url.quality,
+                    codec: None,
+                    width: 0,
+                    height: 0,
+                });
+            }
+        };
+        let video = self.select_video(&dash.video)?;
+        let audio = dash
+            .audio
+            .as_ref()
+            .and_then(|audio| audio.iter().max_by_key(|s| s.bandwidth));
+        Some(Selection {
+            video_url: video.base_url.clone(),
+            video_backup: video.backup_url.clone().unwrap_or_default(),
+            audio_url: audio.map(|a| a.base_url.clone()),
+            audio_backup: audio.and_then(|a| a.backup_url.clone()).unwrap_or_default(),
+            quality: video.id,
+            codec: Codec::from_id(video.codecid),
+            width: video.width,
+            height: video.height,
+        })
+    }
+
+    fn select_video<'a>(&self, streams: &'a [DashStream]) -> Option<&'a DashStream> {
+        let within = |s: &&DashStream| {
+            self.max_height.is_none_or(|h| s.height <= h)
+                && self.max_bandwidth.is_none_or(|b| s.bandwidth <= b)
+        };
+        let mut candidates: Vec<&DashStream> = streams.iter().filter(within).collect();
+        if candidates.is_empty() {
+            // every stream exceeds the limits, take the lowest one unless asked to fail
+            if self.fallback == Fallback::Fail {
+                return None;
+            }
+            let lowest = streams.iter().map(|s| s.id).min()?;
+            candidates = streams.iter().filter(|s| s.id == lowest).collect();
+        }
+        let quality = self.select_quality(candidates.iter().map(|s| s.id))?;
+        candidates
+            .into_iter()
+            .filter(|s| s.id == quality)
+            .min_by_key(|s| (self.codec_rank(s.codecid), std::cmp::Reverse(s.bandwidth)))
+    }
+
+    fn select_quality(&self, offered: impl Iterator<Item = u32> + Clone) -> Option<u32> {
+        let best = offered.clone().max()?;
+        let Some(preferred) = self.quality else {
+            return Some(best);
+        };
+        let lower = offered.clone().filter(|&q| q <= preferred).max();
+        let higher = offered.filter(|&q| q >= preferred).min();
+        match self.fallback {
+            _ if lower == Some(preferred) => lower,
+            Fallback::Lower => lower.or(higher),
+            Fallback::Higher => higher.or(lower),
+            Fallback::Fail => None,
+        }
+    }
+
+    fn codec_rank(&self, codecid: u32) -> usize {
+        Codec::from_id(codecid)
+            .and_then(|c| self.codecs.iter().position(|&p| p == c))
+            .unwrap_or(self.codecs.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::playinfo::Dash;
+
+    fn stream(id: u32, codecid: u32, height: u32, bandwidth: u64) -> DashStream {
+        DashStream {
+            id,
+            base_url: format!("{id}-{codecid}"),
+            codecid,
+            height,
+            bandwidth,
+            ..Default::default()
+        }
+    }
+
+    fn play_url() -> PlayUrl {
+        PlayUrl {
+            dash: Some(Dash {
+                video: vec![
+                    stream(120, 12, 2160, 9000),
+                    stream(80, 7, 1080, 3000),
+                    stream(80, 12, 1080, 2000),
+                    stream(80, 13, 1080, 1500),
+                    stream(64, 7, 720, 1000),
+                ],
+                audio: Some(vec![stream(30216, 0, 0, 64), stream(30280, 0, 0, 192)]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn best_by_default() {
+        let sel = QualityPolicy::default().select(&play_url()).unwrap();
+        assert_eq!(sel.video_url, "120-12");
+        assert_eq!(sel.audio_url.as_deref(), Some("30280-0"));
+        assert_eq!(sel.to_string(), "4K HEVC");
+    }
+
+    #[test]
+    fn codec_preference() {
+        let policy = QualityPolicy {
+            quality: Some(80),
+            codecs: vec![Codec::Av1, Codec::Hevc],
+            ..Default::default()
+        };
+        let sel = policy.select(&play_url()).unwrap();
+        assert_eq!(sel.video_url, "80-13");
+        assert_eq!(sel.to_string(), "1080P AV1");
+    }
+
+    #[test]
+    fn limits_and_fallback() {
+        let policy = QualityPolicy {
+            max_height: Some(1080),
+            ..Default::default()
+        };
+        assert_eq!(policy.select(&play_url()).unwrap().video_url, "80-7");
+
+        let policy = QualityPolicy {
+            quality: Some(74),
+            ..Default::default()
+        };
+        assert_eq!(policy.select(&play_url()).unwrap().quality, 64);
+
+        let policy = QualityPolicy {
+            quality: Some(74),
+            fallback: Fallback::Higher,
+            ..Default::default()
+        };
+        assert_eq!(policy.select(&play_url()).unwrap().quality, 80);
+
+        let policy = QualityPolicy {
+            quality: Some(74),
+            fallback: Fallback::Fail,
+            ..Default::default()
+        };
+        assert!(policy.select(&play_url()).is_none());
+
+        let policy = QualityPolicy {
+            max_bandwidth: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(policy.select(&play_url()).unwrap().quality, 64);
+    }
+}
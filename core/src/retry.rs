@@ -0,0 +1,66 @@
+//! How failed requests are retried
+
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// Retries of a request, or of a range that keeps breaking off
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Attempts after the first one before giving up
+    pub max_attempts: u32,
+    /// In milliseconds, doubled for every attempt
+    pub base_delay: u64,
+    /// In milliseconds
+    pub max_delay: u64,
+    /// The HTTP statuses worth trying again, the others fail at once.
+    /// 403 is not among them, it means the urls have expired
+    pub statuses: Vec<u16>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay: 500,
+            max_delay: 30_000,
+            statuses: vec![408, 429, 500, 502, 503, 504],
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn retryable(&self, status: u16) -> bool {
+        self.statuses.contains(&status)
+    }
+
+    /// The wait before attempt `attempt`, counted from 1.
+    /// A random half of it is cut off, so that the range workers don't retry all at once
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(1 << attempt.saturating_sub(1).min(32))
+            .min(self.max_delay);
+        let jitter = RandomState::new().build_hasher().finish() % (delay / 2 + 1);
+        Duration::from_millis(delay - jitter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff() {
+        let policy = RetryPolicy::default();
+        for (attempt, max) in [(1, 500), (2, 1000), (3, 2000), (10, 30_000), (100, 30_000)] {
+            let delay = policy.delay(attempt).as_millis() as u64;
+            assert!(max / 2 <= delay && delay <= max, "{attempt}: {delay}");
+        }
+        assert!(policy.retryable(503));
+        assert!(!policy.retryable(403));
+        assert!(!policy.retryable(404));
+    }
+}
@@ -0,0 +1,160 @@
+//! The queue of the executor.
+//! Queued tasks are started by priority, then in the order of queueing,
+//! while the active tasks and their connections are kept under `QueueConfig`
+
+use std::collections::HashMap;
+
+use crate::config::QueueConfig;
+
+#[derive(Debug)]
+struct Entry {
+    id: usize,
+    priority: i32,
+    // the order of queueing, smaller is earlier
+    seq: i64,
+}
+
+#[derive(Debug)]
+pub(crate) struct Scheduler {
+    limits: QueueConfig,
+    queue: Vec<Entry>,
+    // id -> connections it may open
+    active: HashMap<usize, usize>,
+    // kept for the tasks queued again
+    priorities: HashMap<usize, i32>,
+    seq: i64,
+}
+
+impl Scheduler {
+    pub fn new(limits: QueueConfig) -> Self {
+        Self {
+            limits,
+            queue: Vec::new(),
+            active: HashMap::new(),
+            priorities: HashMap::new(),
+            seq: 0,
+        }
+    }
+
+    pub fn set_limits(&mut self, limits: QueueConfig) {
+        self.limits = limits;
+    }
+
+    /// Queue `id` behind the ones of the same priority
+    pub fn push(&mut self, id: usize) {
+        if self.queue.iter().any(|e| e.id == id) {
+            return;
+        }
+        self.seq += 1;
+        self.queue.push(Entry {
+            id,
+            priority: self.priorities.get(&id).copied().unwrap_or(0),
+            seq: self.seq,
+        });
+    }
+
+    /// Take `id` out of the queue, such as when it's cancelled before starting
+    pub fn remove(&mut self, id: usize) {
+        self.queue.retain(|e| e.id != id);
+    }
+
+    /// Higher is started earlier, the default is 0
+    pub fn set_priority(&mut self, id: usize, priority: i32) {
+        self.priorities.insert(id, priority);
+        if let Some(entry) = self.queue.iter_mut().find(|e| e.id == id) {
+            entry.priority = priority;
+        }
+    }
+
+    /// Make `id` the next one to start
+    pub fn move_to_top(&mut self, id: usize) {
+        let priority = self.queue.iter().map(|e| e.priority).max().unwrap_or(0);
+        let seq = self.queue.iter().map(|e| e.seq).min().unwrap_or(0) - 1;
+        if let Some(entry) = self.queue.iter_mut().find(|e| e.id == id) {
+            entry.priority = priority;
+            entry.seq = seq;
+            self.priorities.insert(id, priority);
+        }
+    }
+
+    /// `id` stopped running, its slot is free
+    pub fn finish(&mut self, id: usize) {
+        self.active.remove(&id);
+    }
+
+    /// The next task to start if the limits allow, it's active from now on.
+    /// The first task in the queue waits for room rather than being overtaken by smaller ones,
+    /// but a task wider than `max_connections` still starts when nothing else runs
+    pub fn next(&mut self, connections: impl Fn(usize) -> usize) -> Option<usize> {
+        let (index, entry) = self
+            .queue
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, e)| (e.priority, -e.seq))?;
+        let needed = connections(entry.id);
+        let opened: usize = self.active.values().sum();
+        let fits = self.active.is_empty()
+            || (self.active.len() < self.limits.max_active
+                && opened + needed <= self.limits.max_connections);
+        if !fits {
+            return None;
+        }
+        let entry = self.queue.remove(index);
+        self.active.insert(entry.id, needed);
+        Some(entry.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain(scheduler: &mut Scheduler) -> Vec<usize> {
+        std::iter::from_fn(|| scheduler.next(|_| 2)).collect()
+    }
+
+    #[test]
+    fn limits() {
+        let mut scheduler = Scheduler::new(QueueConfig {
+            max_active: 2,
+            max_connections: 8,
+        });
+        for id in 0..4 {
+            scheduler.push(id);
+        }
+        assert_eq!(drain(&mut scheduler), vec![0, 1]);
+        scheduler.finish(0);
+        assert_eq!(drain(&mut scheduler), vec![2]);
+        // 6 connections are open, 4 more are too many
+        scheduler.finish(1);
+        scheduler.finish(2);
+        scheduler.push(5);
+        assert_eq!(scheduler.next(|_| 2), Some(3));
+        assert_eq!(scheduler.next(|_| 7), None);
+        scheduler.finish(3);
+        // too wide, but nothing else runs
+        assert_eq!(scheduler.next(|_| 9), Some(5));
+        assert_eq!(scheduler.next(|_| 2), None);
+    }
+
+    #[test]
+    fn priorities() {
+        let mut scheduler = Scheduler::new(QueueConfig {
+            max_active: 10,
+            max_connections: 100,
+        });
+        for id in 0..5 {
+            scheduler.push(id);
+        }
+        scheduler.set_priority(3, 1);
+        scheduler.set_priority(1, -1);
+        scheduler.move_to_top(4);
+        scheduler.remove(2);
+        assert_eq!(drain(&mut scheduler), vec![4, 3, 0, 1]);
+        // the priority is kept when queued again
+        scheduler.finish(1);
+        scheduler.push(6);
+        scheduler.push(1);
+        assert_eq!(drain(&mut scheduler), vec![6, 1]);
+    }
+}
@@ -0,0 +1,207 @@
+//! Uploader space (UP主), list the uploads of `space.bilibili.com/<mid>`
+
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+use crate::error::Error;
+use crate::helper::{self, ApiResponse};
+use crate::wbi;
+
+type SpaceResult<T> = Result<T, Error>;
+
+/// Items per request, the max the api allows
+const PAGE_SIZE: usize = 30;
+
+/// Find the mid in a space url, such as `https://space.bilibili.com/32280488/video`
+pub fn mid(target: &str) -> Option<u64> {
+    let re = regex::Regex::new(r"space\.bilibili\.com/(\d+)").unwrap();
+    re.captures(target)?.get(1)?.as_str().parse().ok()
+}
+
+/// Which uploads to download
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct SpaceFilter {
+    /// Unix timestamp, uploads published before it are skipped
+    pub since: Option<i64>,
+    /// Unix timestamp, uploads published after it are skipped
+    pub until: Option<i64>,
+    /// Only the titles containing it, case insensitive
+    pub keyword: Option<String>,
+    pub max_count: Option<usize>,
+    /// Only the uploads newer than the newest one enqueued by the last incremental run
+    pub incremental: bool,
+}
+
+impl SpaceFilter {
+    fn matches(&self, upload: &Upload) -> bool {
+        self.since.is_none_or(|since| upload.created >= since)
+            && self.until.is_none_or(|until| upload.created <= until)
+            && self.keyword.as_ref().is_none_or(|keyword| {
+                upload
+                    .title
+                    .to_lowercase()
+                    .contains(&keyword.to_lowercase())
+            })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct SearchData {
+    list: VList,
+    page: PageInfo,
+}
+
+#[derive(Deserialize, Debug)]
+struct VList {
+    #[serde(default)]
+    vlist: Vec<Upload>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PageInfo {
+    count: usize,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Upload {
+    pub aid: u64,
+    pub bvid: String,
+    pub title: String,
+    #[serde(default)]
+    pub author: String,
+    /// Unix timestamp of publishing
+    pub created: i64,
+}
+
+/// What the last incremental run of a space enqueued
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SyncState {
+    last_created: i64,
+    last_bvid: String,
+}
+
+fn state_path(mid: u64) -> SpaceResult<std::path::PathBuf> {
+    Ok(std::path::Path::new(config::save_path()?).join(format!(".space_{mid}.json")))
+}
+
+fn load_state(mid: u64) -> Option<SyncState> {
+    let json = std::fs::read_to_string(state_path(mid).ok()?).ok()?;
+    serde_json::from_str(&json).ok()
+}
+
+fn save_state(mid: u64, state: &SyncState) -> SpaceResult<()> {
+    std::fs::create_dir_all(config::save_path()?)?;
+    Ok(std::fs::write(
+        state_path(mid)?,
+        serde_json::to_string(state)?,
+    )?)
+}
+
+/// Page through the uploads of `mid`, newest first, and keep the ones matching `filter`.
+/// An incremental run starts after the upload last passed to `mark_synced`
+pub(crate) async fn list(
+    client: &reqwest::Client,
+    mid: u64,
+    filter: &SpaceFilter,
+) -> SpaceResult<Vec<Upload>> {
+    let mut filter = filter.clone();
+    if filter.incremental {
+        if let Some(state) = load_state(mid) {
+            let after_last = state.last_created + 1;
+            filter.since = Some(filter.since.map_or(after_last, |s| s.max(after_last)));
+        }
+    }
+    let key = wbi::fetch_key(client).await?;
+    let mut uploads = Vec::new();
+    let mut pn = 1;
+    'pages: loop {
+        let query = wbi::sign(
+            &[
+                ("mid", mid.to_string()),
+                ("ps", PAGE_SIZE.to_string()),
+                ("pn", pn.to_string()),
+                ("order", "pubdate".to_owned()),
+            ],
+            &key,
+        );
+        let resp: ApiResponse<SearchData> = helper::get_json(
+            client,
+            &format!("https://api.bilibili.com/x/space/wbi/arc/search?{query}"),
+        )
+        .await?;
+        let data = resp.data()?;
+        let gotten = data.list.vlist.len();
+        for upload in data.list.vlist {
+            // newest first, the rest are all older
+            if filter.since.is_some_and(|since| upload.created < since) {
+                break 'pages;
+            }
+            if filter.max_count.is_some_and(|max| uploads.len() >= max) {
+                break 'pages;
+            }
+            if filter.matches(&upload) {
+                uploads.push(upload);
+            }
+        }
+        if gotten == 0 || pn * PAGE_SIZE >= data.page.count {
+            break;
+        }
+        pn += 1;
+    }
+    Ok(uploads)
+}
+
+/// Record `newest` as the last upload of `mid` downloaded,
+/// an incremental run lists only the ones published after it
+pub(crate) fn mark_synced(mid: u64, newest: &Upload) {
+    let state = SyncState {
+        last_created: newest.created,
+        last_bvid: newest.bvid.to_owned(),
+    };
+    if let Err(e) = save_state(mid, &state) {
+        println!("Failed to record the sync state of space {mid}: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_mid() {
+        assert_eq!(mid("https://space.bilibili.com/32280488"), Some(32280488));
+        assert_eq!(
+            mid("https://space.bilibili.com/32280488/video?tid=0"),
+            Some(32280488)
+        );
+        assert_eq!(mid("https://www.bilibili.com/video/BV1Ao4y1b7fj/"), None);
+    }
+
+    #[test]
+    fn filter_uploads() {
+        let upload = Upload {
+            title: "Rust Tutorial 01".to_owned(),
+            created: 1_700_000_000,
+            ..Default::default()
+        };
+        assert!(SpaceFilter::default().matches(&upload));
+        let filter = SpaceFilter {
+            keyword: Some("rust".to_owned()),
+            since: Some(1_690_000_000),
+            until: Some(1_710_000_000),
+            ..Default::default()
+        };
+        assert!(filter.matches(&upload));
+        let filter = SpaceFilter {
+            keyword: Some("go".to_owned()),
+            ..Default::default()
+        };
+        assert!(!filter.matches(&upload));
+        let filter = SpaceFilter {
+            since: Some(1_700_000_001),
+            ..Default::default()
+        };
+        assert!(!filter.matches(&upload));
+    }
+}
@@ -3,29 +3,82 @@
 
 use std::sync::atomic::{AtomicUsize, Ordering};
 
-// working pausing cancelled finished
-const STATENUM: usize = 4;
-// siwtch cancel finish
-const TRIGGERNUM: usize = 3;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Working,
+    Pausing,
+    Cancelled,
+    Finished,
+    Failed,
+    /// Waiting for the executor to start it
+    Queued,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Switch,
+    Cancel,
+    Finish,
+    Fail,
+    /// Queue a failed or cancelled task again
+    Retry,
+    /// Begin a queued task
+    Start,
+}
+
+impl State {
+    /// The code exposed by `Downloader::state`
+    pub fn code(self) -> usize {
+        match self {
+            State::Working => 0,
+            State::Pausing => 1,
+            State::Cancelled => 2,
+            State::Finished => 3,
+            State::Failed => 4,
+            State::Queued => 5,
+        }
+    }
+
+    pub fn from_code(code: usize) -> Self {
+        match code {
+            0 => State::Working,
+            1 => State::Pausing,
+            2 => State::Cancelled,
+            3 => State::Finished,
+            4 => State::Failed,
+            5 => State::Queued,
+            _ => unreachable!(),
+        }
+    }
+
+    /// The transition matrix, a trigger not listed keeps the state
+    fn on(self, trigger: Trigger) -> Self {
+        use State::*;
+        use Trigger::*;
+        match (self, trigger) {
+            (Working, Switch) => Pausing,
+            (Pausing, Switch) => Working,
+            (Working | Pausing | Queued, Cancel) => Cancelled,
+            (Working | Pausing, Finish) => Finished,
+            (Working | Pausing, Fail) => Failed,
+            (Cancelled | Failed, Retry) => Queued,
+            (Queued, Start) => Working,
+            (state, _) => state,
+        }
+    }
+}
 
 #[allow(clippy::upper_case_acronyms)]
 #[derive(Debug)]
 pub struct FSM {
-    matrix: [[usize; STATENUM]; TRIGGERNUM],
     c: AtomicUsize,
 }
 
 impl FSM {
+    /// A task is queued until it's started
     pub fn new() -> Self {
-        let matrix = [
-            // working pausing cancelled finished
-            /*siwtch*/ [1, 0, 2, 3],
-            /*cancel*/ [2, 2, 2, 3],
-            /*finish*/ [3, 3, 2, 3],
-        ];
         Self {
-            matrix,
-            c: AtomicUsize::new(0),
+            c: AtomicUsize::new(State::Queued.code()),
         }
     }
 
@@ -33,44 +86,49 @@ impl FSM {
         self.c.load(Ordering::Relaxed)
     }
 
-    #[allow(unused)]
-    fn now(&self) -> State {
-        match self.c.load(Ordering::Relaxed) {
-            0 => State::Working,
-            1 => State::Pausing,
-            2 => State::Cancelled,
-            3 => State::Finished,
-            _ => unreachable!(),
-        }
+    pub fn now(&self) -> State {
+        State::from_code(self.c.load(Ordering::Relaxed))
     }
 
     pub fn switch(&self) {
-        self.change_state(0);
+        self.change_state(Trigger::Switch);
     }
 
     pub fn cancel(&self) {
-        self.change_state(1);
+        self.change_state(Trigger::Cancel);
     }
 
     pub fn finish(&self) {
-        self.change_state(2);
+        self.change_state(Trigger::Finish);
     }
 
-    fn change_state(&self, trigger: usize) {
-        let c = self.c.load(Ordering::Relaxed);
-        let new = self.matrix[trigger][c];
-        self.c
-            .fetch_update(Ordering::SeqCst, Ordering::Relaxed, |_| Some(new))
-            .unwrap();
+    pub fn fail(&self) {
+        self.change_state(Trigger::Fail);
     }
-}
 
-#[derive(Debug)]
-pub enum State {
-    Working,
-    Pausing,
-    Cancelled,
-    Finished,
+    /// Return true if it was queued and is working now
+    pub fn start(&self) -> bool {
+        self.change_state(Trigger::Start) == State::Queued
+    }
+
+    /// Return true if it was failed or cancelled and is queued now
+    pub fn retry(&self) -> bool {
+        matches!(
+            self.change_state(Trigger::Retry),
+            State::Cancelled | State::Failed
+        )
+    }
+
+    /// Return the state before the trigger
+    fn change_state(&self, trigger: Trigger) -> State {
+        let old = self
+            .c
+            .fetch_update(Ordering::SeqCst, Ordering::Relaxed, |c| {
+                Some(State::from_code(c).on(trigger).code())
+            })
+            .unwrap();
+        State::from_code(old)
+    }
 }
 
 #[cfg(test)]
@@ -81,6 +139,8 @@ mod tests {
     fn test() {
         let fsm = FSM::new();
         dbg!("init", fsm.now());
+        fsm.start();
+        dbg!("start", fsm.now());
         fsm.switch();
         dbg!("switch", fsm.now());
         fsm.switch();
@@ -90,4 +150,37 @@ mod tests {
         fsm.switch();
         dbg!("switch after cancel", fsm.now());
     }
+
+    #[test]
+    fn fail() {
+        let fsm = FSM::new();
+        fsm.start();
+        fsm.switch();
+        fsm.fail();
+        assert_eq!(fsm.now_state_code(), 4);
+        fsm.switch();
+        fsm.cancel();
+        assert_eq!(fsm.now_state_code(), 4);
+        let fsm = FSM::new();
+        fsm.cancel();
+        fsm.fail();
+        assert_eq!(fsm.now_state_code(), 2);
+        assert!(!fsm.start());
+    }
+
+    #[test]
+    fn retry() {
+        let fsm = FSM::new();
+        assert!(fsm.start());
+        assert!(!fsm.retry());
+        fsm.fail();
+        assert!(fsm.retry());
+        assert_eq!(fsm.now(), State::Queued);
+        fsm.cancel();
+        assert!(fsm.retry());
+        assert!(fsm.start());
+        fsm.finish();
+        assert!(!fsm.retry());
+        assert_eq!(fsm.now(), State::Finished);
+    }
 }
@@ -0,0 +1,161 @@
+//! The task journal, a JSON-lines file in the app data dir.
+//! Every change of a task appends a line, the last line of an id wins
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::Error;
+use crate::helper;
+
+type StoreResult<T> = Result<T, Error>;
+
+pub(crate) static JOURNAL: once_cell::sync::Lazy<Journal> =
+    once_cell::sync::Lazy::new(|| Journal::new(helper::store_dir().join("tasks.jsonl")));
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TaskRecord {
+    pub id: usize,
+    pub target: String,
+    pub title: String,
+    /// Where the video is saved, empty unless finished
+    pub path: String,
+    /// In bytes
+    pub size: usize,
+    /// The same code as `Downloader::state`
+    pub state: usize,
+    /// Unix timestamp of adding
+    pub created: u64,
+    /// Unix timestamp of the last change
+    pub updated: u64,
+    pub error: Option<Error>,
+    /// Finished without downloading, it was in the download archive
+    #[serde(default)]
+    pub skipped: bool,
+}
+
+#[derive(Debug)]
+pub(crate) struct Journal {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl Journal {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn append(&self, record: &TaskRecord) -> StoreResult<()> {
+        let _lock = self.lock.lock().map_err(|e| Error::Io(e.to_string()))?;
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        Ok(file.write_all(line.as_bytes())?)
+    }
+
+    /// The latest record of every task, in the order of adding.
+    /// Broken lines, such as the last one when the app is killed while writing, are skipped
+    pub fn load(&self) -> Vec<TaskRecord> {
+        let _lock = self.lock.lock();
+        let Ok(journal) = std::fs::read_to_string(&self.path) else {
+            return Vec::new();
+        };
+        let mut latest: HashMap<usize, TaskRecord> = HashMap::new();
+        for record in journal
+            .lines()
+            .filter_map(|line| serde_json::from_str::<TaskRecord>(line).ok())
+        {
+            latest.insert(record.id, record);
+        }
+        let mut records: Vec<TaskRecord> = latest.into_values().collect();
+        records.sort_by_key(|r| (r.created, r.id));
+        records
+    }
+
+    /// Rewrite the journal with only the latest records,
+    /// which also drops a broken last line so that appending after it is safe
+    pub fn compact(&self) -> StoreResult<()> {
+        let records = self.load();
+        let _lock = self.lock.lock().map_err(|e| Error::Io(e.to_string()))?;
+        let journal: String = records
+            .iter()
+            .filter_map(|r| serde_json::to_string(r).ok())
+            .map(|line| line + "\n")
+            .collect();
+        let tmp = self.path.with_extension("jsonl.tmp");
+        std::fs::write(&tmp, journal)?;
+        Ok(std::fs::rename(tmp, &self.path)?)
+    }
+
+    /// The id after all the recorded ones
+    pub fn next_id(&self) -> usize {
+        self.load().iter().map(|r| r.id + 1).max().unwrap_or(0)
+    }
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: usize, state: usize, created: u64) -> TaskRecord {
+        TaskRecord {
+            id,
+            target: format!("https://www.bilibili.com/video/BV{id}/"),
+            title: String::new(),
+            path: String::new(),
+            size: 0,
+            state,
+            created,
+            updated: created,
+            error: None,
+            skipped: false,
+        }
+    }
+
+    #[test]
+    fn journal() {
+        let path = std::env::temp_dir().join("bili_journal_test/tasks.jsonl");
+        let _ = std::fs::remove_file(&path);
+        let journal = Journal::new(path.clone());
+        assert_eq!(journal.next_id(), 0);
+        journal.append(&record(1, 0, 20)).unwrap();
+        journal.append(&record(0, 0, 10)).unwrap();
+        let failed = TaskRecord {
+            error: Some(Error::NoStream),
+            ..record(1, 4, 20)
+        };
+        journal.append(&failed).unwrap();
+        // a line cut off by a crash
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"id\":2,\"tar")
+            .unwrap();
+        let records = journal.load();
+        assert_eq!(records, vec![record(0, 0, 10), failed.clone()]);
+        assert_eq!(journal.next_id(), 2);
+        journal.compact().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+        assert_eq!(journal.load(), records);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,294 @@
+//! Parse and normalize what users paste: video urls, bare BV ids, av numbers,
+//! `m.bilibili.com` and `b23.tv` short links, bangumi, favorites, collections and spaces
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::bangumi::PgcTarget;
+use crate::collection::CollectionTarget;
+use crate::config::USER_AGENT;
+use crate::{favorites, space};
+
+const XOR_CODE: u64 = 23442827791579;
+const MASK_CODE: u64 = 2251799813685247;
+const MAX_AID: u64 = 1 << 51;
+const ALPHABET: &[u8; 58] = b"FcwAPNKTMug3GV5Lj7EJnHpWsx4tb8haYeviqBz6rkCy12mUSDQX9RdoZf";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    /// A video, `page` is the page (分P) the url points to
+    Video {
+        bvid: String,
+        page: Option<u32>,
+    },
+    Bangumi(PgcTarget),
+    /// A favorites folder, by media_id
+    Favorites(u64),
+    Collection(CollectionTarget),
+    /// An uploader space, by mid
+    Space(u64),
+    /// A `b23.tv` short link, resolved by `Target::resolve`
+    ShortLink(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TargetError {
+    Empty,
+    /// A url of an unsupported kind of page
+    Unsupported(String),
+    /// Neither a url nor a BV id or av number
+    Invalid(String),
+    /// The short link could not be resolved
+    ShortLink(String),
+}
+
+impl fmt::Display for TargetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TargetError::Empty => write!(f, "the target is empty"),
+            TargetError::Unsupported(target) => write!(f, "unsupported target: {target}"),
+            TargetError::Invalid(target) => write!(f, "invalid target: {target}"),
+            TargetError::ShortLink(reason) => write!(f, "failed to resolve short link: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for TargetError {}
+
+impl FromStr for Target {
+    type Err = TargetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(TargetError::Empty);
+        }
+        if let Some(media_id) = favorites::media_id(s) {
+            return Ok(Target::Favorites(media_id));
+        }
+        if let Some(collection) = CollectionTarget::from_url(s) {
+            return Ok(Target::Collection(collection));
+        }
+        if let Some(mid) = space::mid(s) {
+            return Ok(Target::Space(mid));
+        }
+        if let Some(pgc) = PgcTarget::from_url(s) {
+            return Ok(Target::Bangumi(pgc));
+        }
+        let short =
+            regex::Regex::new(r"^(?:https?://)?(?:b23\.tv|bili2233\.cn)/[0-9A-Za-z]+").unwrap();
+        if let Some(m) = short.find(s) {
+            let link = m.as_str();
+            return Ok(Target::ShortLink(match link.starts_with("http") {
+                true => link.to_owned(),
+                false => format!("https://{link}"),
+            }));
+        }
+        let is_url = s.contains("://") || s.contains("bilibili.com");
+        let video = match is_url {
+            true => {
+                r"^(?:https?://)?(?:www\.|m\.)?bilibili\.com/video/(BV1[1-9A-HJ-NP-Za-km-z]{9}|[aA][vV]\d+)"
+            }
+            false => r"^(BV1[1-9A-HJ-NP-Za-km-z]{9}|[aA][vV]\d+)$",
+        };
+        let video = regex::Regex::new(video).unwrap();
+        let Some(id) = video.captures(s).and_then(|cpt| cpt.get(1)) else {
+            return match is_url {
+                true => Err(TargetError::Unsupported(s.to_owned())),
+                false => Err(TargetError::Invalid(s.to_owned())),
+            };
+        };
+        let id = id.as_str();
+        let bvid = match id.starts_with("BV") {
+            true => id.to_owned(),
+            false => {
+                let aid = id[2..]
+                    .parse()
+                    .ok()
+                    .filter(|&aid| aid > 0 && aid < MAX_AID)
+                    .ok_or_else(|| TargetError::Invalid(s.to_owned()))?;
+                av2bv(aid)
+            }
+        };
+        let page = regex::Regex::new(r"[?&]p=(\d+)")
+            .unwrap()
+            .captures(s)
+            .and_then(|cpt| cpt.get(1)?.as_str().parse().ok())
+            .filter(|&p| p > 0);
+        Ok(Target::Video { bvid, page })
+    }
+}
+
+impl Target {
+    /// Follow the redirect of a short link, other targets are returned as is
+    pub async fn resolve(self) -> Result<Target, TargetError> {
+        let Target::ShortLink(link) = self else {
+            return Ok(self);
+        };
+        let resp = reqwest::Client::new()
+            .get(&link)
+            .header(reqwest::header::USER_AGENT, USER_AGENT)
+            .send()
+            .await
+            .map_err(|e| TargetError::ShortLink(e.to_string()))?;
+        match resp.url().as_str().parse()? {
+            Target::ShortLink(_) => Err(TargetError::ShortLink(format!(
+                "{link} redirects to itself"
+            ))),
+            target => Ok(target),
+        }
+    }
+
+    /// The canonical url of a video or bangumi, `None` for lists
+    pub fn url(&self) -> Option<String> {
+        match self {
+            Target::Video { bvid, page: None } => {
+                Some(format!("https://www.bilibili.com/video/{bvid}/"))
+            }
+            Target::Video {
+                bvid,
+                page: Some(page),
+            } => Some(crate::pages::page_url(bvid, *page)),
+            Target::Bangumi(PgcTarget::Episode(ep_id)) => Some(crate::bangumi::episode_url(*ep_id)),
+            Target::Bangumi(PgcTarget::Season(season_id)) => Some(format!(
+                "https://www.bilibili.com/bangumi/play/ss{season_id}"
+            )),
+            Target::Bangumi(PgcTarget::Media(media_id)) => Some(format!(
+                "https://www.bilibili.com/bangumi/media/md{media_id}"
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// Convert an av number to a BV id
+pub fn av2bv(aid: u64) -> String {
+    let mut bytes = *b"BV1000000000";
+    let mut tmp = (MAX_AID | aid) ^ XOR_CODE;
+    let mut idx = bytes.len() - 1;
+    while tmp > 0 {
+        bytes[idx] = ALPHABET[(tmp % 58) as usize];
+        tmp /= 58;
+        idx -= 1;
+    }
+    bytes.swap(3, 9);
+    bytes.swap(4, 7);
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Convert a BV id to an av number, `None` if `bvid` is invalid
+pub fn bv2av(bvid: &str) -> Option<u64> {
+    let mut bytes: [u8; 12] = bvid.as_bytes().try_into().ok()?;
+    if !bvid.starts_with("BV1") {
+        return None;
+    }
+    bytes.swap(3, 9);
+    bytes.swap(4, 7);
+    let mut tmp = 0u64;
+    for b in &bytes[3..] {
+        let i = ALPHABET.iter().position(|a| a == b)? as u64;
+        tmp = tmp.checked_mul(58)?.checked_add(i)?;
+    }
+    Some((tmp & MASK_CODE) ^ XOR_CODE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video(bvid: &str, page: Option<u32>) -> Target {
+        Target::Video {
+            bvid: bvid.to_owned(),
+            page,
+        }
+    }
+
+    #[test]
+    fn av_bv() {
+        assert_eq!(av2bv(170001), "BV17x411w7KC");
+        assert_eq!(av2bv(1054803170), "BV1mH4y1u7UA");
+        assert_eq!(bv2av("BV17x411w7KC"), Some(170001));
+        assert_eq!(bv2av("BV1Ao4y1b7fj"), Some(397733453));
+        assert_eq!(bv2av("BV1Ao4y1b7f"), None);
+        assert_eq!(bv2av("BV1Ao4y1b70j"), None);
+    }
+
+    #[test]
+    fn parse_videos() {
+        let bv = video("BV1Ao4y1b7fj", None);
+        assert_eq!("BV1Ao4y1b7fj".parse(), Ok(bv.clone()));
+        assert_eq!(" BV1Ao4y1b7fj\n".parse(), Ok(bv.clone()));
+        assert_eq!(
+            "https://www.bilibili.com/video/BV1Ao4y1b7fj/?".parse(),
+            Ok(bv.clone())
+        );
+        assert_eq!("bilibili.com/video/BV1Ao4y1b7fj".parse(), Ok(bv.clone()));
+        assert_eq!(
+            "https://m.bilibili.com/video/BV1Ao4y1b7fj?p=3&share=1".parse(),
+            Ok(video("BV1Ao4y1b7fj", Some(3)))
+        );
+        assert_eq!("av170001".parse(), Ok(video("BV17x411w7KC", None)));
+        assert_eq!(
+            "https://www.bilibili.com/video/av170001/?p=2".parse(),
+            Ok(video("BV17x411w7KC", Some(2)))
+        );
+    }
+
+    #[test]
+    fn parse_others() {
+        assert_eq!(
+            "https://b23.tv/AbC123x".parse(),
+            Ok(Target::ShortLink("https://b23.tv/AbC123x".to_owned()))
+        );
+        assert_eq!(
+            "b23.tv/AbC123x".parse(),
+            Ok(Target::ShortLink("https://b23.tv/AbC123x".to_owned()))
+        );
+        assert_eq!(
+            "https://www.bilibili.com/bangumi/play/ep374717".parse(),
+            Ok(Target::Bangumi(PgcTarget::Episode(374717)))
+        );
+        assert_eq!(
+            "https://space.bilibili.com/32280488/favlist?fid=1052622027".parse(),
+            Ok(Target::Favorites(1052622027))
+        );
+        assert_eq!(
+            "https://space.bilibili.com/32280488".parse(),
+            Ok(Target::Space(32280488))
+        );
+    }
+
+    #[test]
+    fn reject_garbage() {
+        assert_eq!("".parse::<Target>(), Err(TargetError::Empty));
+        assert!(matches!(
+            "hello world".parse::<Target>(),
+            Err(TargetError::Invalid(_))
+        ));
+        assert!(matches!(
+            "BV1Ao4y1b7f".parse::<Target>(),
+            Err(TargetError::Invalid(_))
+        ));
+        assert!(matches!(
+            "av0".parse::<Target>(),
+            Err(TargetError::Invalid(_))
+        ));
+        assert!(matches!(
+            "https://www.bilibili.com/read/cv123".parse::<Target>(),
+            Err(TargetError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn canonical_url() {
+        assert_eq!(
+            video("BV1Ao4y1b7fj", None).url().unwrap(),
+            "https://www.bilibili.com/video/BV1Ao4y1b7fj/"
+        );
+        assert_eq!(
+            video("BV1Ao4y1b7fj", Some(2)).url().unwrap(),
+            "https://www.bilibili.com/video/BV1Ao4y1b7fj/?p=2"
+        );
+        assert_eq!(Target::Space(1).url(), None);
+    }
+}
@@ -1,172 +1,654 @@
 //! The task, including execute, operations and query functions.
 
 use reqwest::{header, Client};
+use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
-use std::io::SeekFrom;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::Arc;
-use tokio::io::{AsyncSeekExt, AsyncWriteExt};
 use tokio::sync::Mutex;
 use tokio::task::JoinSet;
 
+use crate::archive::{self, ARCHIVE};
+use crate::bangumi::{self, PgcTarget};
 use crate::config::*;
+use crate::error::Error;
+use crate::events::{Event, Events};
 use crate::headers::HeadersGen;
 use crate::helper;
-use crate::process::Process;
-use crate::state::FSM;
+use crate::limiter::{Limits, RateLimiter};
+use crate::manifest::{self, Manifest};
+use crate::mirrors::{self, Mirrors};
+use crate::pages::Pages;
+use crate::playinfo::ParsedMedia;
+use crate::process::{Phase, Process, Progress, StreamCount};
+use crate::quality::{Codec, QualityPolicy, Selection};
+use crate::retry::RetryPolicy;
+use crate::state::{State, FSM};
+use crate::store::{self, TaskRecord};
 
-type TaskResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+type TaskResult<T> = Result<T, Error>;
+
+/// Per task options, passed to `Downloader::add_task`
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct TaskOptions {
+    /// Use this instead of the `QualityPolicy` in config
+    pub quality: Option<QualityPolicy>,
+    /// Expand a multi-part video into one task per selected page,
+    /// or a bangumi season into one task per selected episode.
+    /// `None` for only the page or episode the url points to
+    pub pages: Option<Pages>,
+    /// Download the whole collection (合集) the video belongs to
+    pub collection: bool,
+    /// Save as this file name, without extension, instead of the title
+    pub name: Option<String>,
+    /// Download even if it's in the download archive
+    pub force: bool,
+}
+
+impl TaskOptions {
+    /// The `QualityPolicy` in use, the one in config if not set
+    pub(crate) fn policy(&self) -> QualityPolicy {
+        self.quality
+            .clone()
+            .unwrap_or_else(|| QUALITY.get().cloned().unwrap_or_default())
+    }
+}
+
+/// How a range of a stream went, see `Downloader::range_timings`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RangeTiming {
+    /// The file name of the stream in the cache
+    pub stream: String,
+    pub start: usize,
+    /// Inclusive, earlier than requested if an idle worker took over the rest
+    pub end: usize,
+    /// In milliseconds, from the first request to the last byte
+    pub elapsed: u64,
+    /// In milliseconds, waiting on the network rather than on the rate limits
+    pub waited: u64,
+    /// Requested again after breaking off
+    pub retries: u32,
+    /// The host it finished on
+    pub mirror: String,
+}
 
 #[derive(Debug)]
 pub struct Task {
     pub id: usize,
     target: String,
     save_dir: String,
+    policy: QualityPolicy,
+    name: Option<String>,
+    force: bool,
+    // how many ranges are downloaded at the same time
+    parts: usize,
+    // shared with the other tasks of the downloader
+    client: Client,
+    // of the streams being downloaded
+    mirrors: std::sync::Mutex<Vec<Arc<Mirrors>>>,
+    limits: Limits,
     title: Arc<Mutex<RefCell<String>>>,
+    quality: Arc<Mutex<RefCell<String>>>,
+    error: std::sync::Mutex<Option<Error>>,
+    // finished by the download archive
+    skipped: AtomicBool,
+    // what has been written, saved in the cache dir
+    manifest: Arc<std::sync::Mutex<Manifest>>,
+    // held by the run in progress
+    run: Mutex<()>,
+    created: u64,
     process: Arc<Process>,
     fsm: Arc<FSM>,
+    // to the subscribers of the downloader
+    events: Events,
 }
 
 impl Task {
-    pub fn new(id: usize, target: String) -> Self {
+    /// A task with its own client, see `Task::with_client`
+    pub fn new(id: usize, target: String, options: TaskOptions) -> Self {
+        Self::with_client(id, target, options, helper::client(&http()))
+    }
+
+    /// A task sending every request with `client`,
+    /// so that the connections are reused by the ranges and the other tasks
+    pub fn with_client(id: usize, target: String, options: TaskOptions, client: Client) -> Self {
         let process = Arc::new(Process::new());
+        let policy = options.policy();
+        let name = options.name.clone();
+        let force = options.force;
+        let manifest = Manifest {
+            id,
+            target: target.to_owned(),
+            options,
+            ..Default::default()
+        };
         Self {
             id,
             target,
-            save_dir: SAVE_PATH.get().unwrap().to_owned(),
+            // checked on executing
+            save_dir: SAVE_PATH.get().cloned().unwrap_or_default(),
+            policy,
+            name,
+            force,
+            parts: PARTS.get().copied().unwrap_or(1),
+            client,
+            mirrors: std::sync::Mutex::new(Vec::new()),
+            limits: Limits::default(),
             title: Arc::new(Mutex::new(RefCell::new(String::new()))),
+            quality: Arc::new(Mutex::new(RefCell::new(String::new()))),
+            error: std::sync::Mutex::new(None),
+            skipped: AtomicBool::new(false),
+            manifest: Arc::new(std::sync::Mutex::new(manifest)),
+            run: Mutex::new(()),
+            created: store::now(),
             process,
             fsm: Arc::new(FSM::new()),
+            events: Events::default(),
         }
     }
 
+    /// Continue a task left by the last run, only the chunks not written yet are downloaded
+    pub fn resume(manifest: Manifest, client: Client) -> Self {
+        let task = Self::with_client(
+            manifest.id,
+            manifest.target.to_owned(),
+            manifest.options.clone(),
+            client,
+        );
+        if let Ok(mut m) = task.manifest.lock() {
+            *m = manifest;
+        }
+        task
+    }
+
+    /// Start the queued task, download, merge and remove the cache.
+    /// On failure the state turns `Failed` and the error is kept for `Task::error`.
+    /// Being cancelled is not a failure, `Ok` is returned.
+    /// The cache is kept unless finished, so that `Task::retry` could reuse it
     pub async fn execute(&self) -> TaskResult<()> {
-        helper::mkdir(format!("{}/cache_{}/", self.save_dir, self.id)).await;
-        let (v_url, a_url, title) = self.parse().await?;
-        dbg!(&v_url, &a_url, &title);
-        {
-            let title_ = self.title.lock().await;
-            title_.replace(title.clone());
+        self.transition(FSM::start);
+        // a retry before the last run stopped waits for it,
+        // the last run goes on if it hasn't noticed being cancelled
+        let _run = self.run.lock().await;
+        if self.fsm.now() != State::Working {
+            // ended by the last run, or cancelled before starting
+            return match self.error() {
+                None | Some(Error::Cancelled) => Ok(()),
+                Some(e) => Err(e),
+            };
         }
-        let cache_path = |f| format!("{}/cache_{}/{title}.{f}", self.save_dir, self.id);
-        let v_path = cache_path(VIDEO_FORMAT);
-        let a_path = cache_path(AUDIO_FORMAT);
-        let target_path = vec![(v_url, v_path.clone()), (a_url, a_path.clone())];
-        let res = self.download(target_path).await?;
-        match res {
-            true => {
-                let out_path = format!("{}/{title}.{VIDEO_FORMAT}", self.save_dir);
-                helper::merge(v_path, a_path, out_path).await.unwrap();
-                self.fsm.finish();
+        if let Ok(mut error) = self.error.lock() {
+            // left by the last run after the retry
+            *error = None;
+        }
+        self.skipped.store(false, Ordering::Relaxed);
+        self.save_record();
+        let res = tokio::select! {
+            res = self.try_execute() => res,
+            never = self.report_progress() => match never {},
+        };
+        match &res {
+            Ok(()) => {
+                self.process.set_phase(Phase::Done);
+                self.transition(FSM::finish);
                 println!("Task {} Finished", self.id);
             }
-            false => {
-                self.fsm.cancel();
+            // already cancelled, or started again by a retry waiting for this run
+            Err(Error::Cancelled) => {
                 println!("Task {} Cancelled", self.id);
             }
+            Err(Error::AlreadyDownloaded(_)) => {
+                self.skipped.store(true, Ordering::Relaxed);
+                self.process.set_phase(Phase::Done);
+                self.transition(FSM::finish);
+                println!("Task {} Skipped, already downloaded", self.id);
+            }
+            Err(e) => {
+                self.transition(FSM::fail);
+                println!("Task {} Failed: {e}", self.id);
+            }
+        }
+        match &res {
+            Ok(()) | Err(Error::AlreadyDownloaded(_)) => self.rm_cache(),
+            Err(e) => {
+                if let Ok(mut error) = self.error.lock() {
+                    *error = Some(e.clone());
+                }
+            }
+        }
+        self.save_record();
+        let id = self.id;
+        match &res {
+            Ok(()) | Err(Error::AlreadyDownloaded(_)) => self.events.send(Event::Finished {
+                id,
+                path: self.out_path(&self.title()),
+                skipped: self.skipped(),
+            }),
+            Err(Error::Cancelled) => {}
+            Err(e) => self.events.send(Event::Failed {
+                id,
+                error: e.clone(),
+            }),
+        }
+        match res {
+            Err(Error::Cancelled | Error::AlreadyDownloaded(_)) => Ok(()),
+            res => res,
         }
-        self.rm_cache();
-        Ok(())
     }
 
-    /// A helper function for `Task::execute()`
-    /// Parse a video name
-    /// Return the `video_url`, `audio_url` and `title`
-    async fn parse(&self) -> TaskResult<(String, String, String)> {
-        let client = Client::new();
-        let resp = client
-            .get(&self.target)
-            .header(header::COOKIE, COOKIE.get().unwrap())
-            .header(header::USER_AGENT, USER_AGENT)
-            .send()
-            .await?;
-        let html = resp.text().await.unwrap();
+    /// Send the progress every second while working, until dropped with the execution
+    async fn report_progress(&self) -> std::convert::Infallible {
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            if self.fsm.now() == State::Working {
+                self.events.send(Event::Progress {
+                    id: self.id,
+                    progress: self.progress(),
+                    mirrors: self.mirrors(),
+                });
+            }
+        }
+    }
 
-        let re = regex::Regex::new(r#"\[\{"id":\d*,"baseUrl":"(.*?)""#).unwrap();
-        let mut res = re.captures_iter(&html);
-        let match_res = |cpt: regex::Captures| cpt.get(1).unwrap().as_str().to_owned();
-        let v_url = match_res(res.next().unwrap());
-        let a_url = match_res(res.next().unwrap());
+    /// Change the state by `trigger`, and tell the subscribers if it changed
+    fn transition<R>(&self, trigger: impl FnOnce(&FSM) -> R) -> R {
+        let before = self.fsm.now();
+        let res = trigger(&self.fsm);
+        let now = self.fsm.now();
+        if now != before {
+            self.events.send(Event::StateChanged {
+                id: self.id,
+                state: now.code(),
+            });
+        }
+        res
+    }
 
-        let re = regex::Regex::new(r#""videoData":\{.+?"title":"(.*?)",""#).unwrap();
-        let title = helper::file_name_filter(&match_res(re.captures(&html).unwrap()));
-        Ok((v_url, a_url, title))
+    async fn try_execute(&self) -> TaskResult<()> {
+        if self.save_dir.is_empty() {
+            return Err(Error::Config("the save path is not set".to_owned()));
+        }
+        self.process.reset();
+        helper::mkdir(self.cache_dir()).await?;
+        let media = parse(&self.client, &self.target).await?;
+        let selection = media.select(&self.policy).ok_or(Error::NoStream)?;
+        let key = archive::key(&media, &selection);
+        let duration = match media.play_url.timelength {
+            0 => None,
+            ms => Some(ms as f64 / 1000.0),
+        };
+        let title = match &self.name {
+            Some(name) => helper::file_name_filter(name),
+            None => media.title,
+        };
+        {
+            let title_ = self.title.lock().await;
+            title_.replace(title.clone());
+        }
+        self.events.send(Event::TitleResolved {
+            id: self.id,
+            title: title.clone(),
+        });
+        // recorded with the title, so that the path is where it was saved before
+        if !self.force && ARCHIVE.contains(&key) {
+            return Err(Error::AlreadyDownloaded(title));
+        }
+        println!("Task {} chose {selection} for {title}", self.id);
+        {
+            let quality = self.quality.lock().await;
+            quality.replace(selection.to_string());
+        }
+        self.update_manifest(|m| {
+            m.title = title.to_owned();
+            m.quality = selection.to_string();
+        })
+        .await?;
+        let refresher = Refresher::new(&self.client, &self.target, &self.policy, &selection);
+        let video = Arc::new(Mirrors::new(selection.video_url, selection.video_backup));
+        let cache_path = |f| format!("{}/{title}.{f}", self.cache_dir().display());
+        let v_path = cache_path(VIDEO_FORMAT);
+        let a_path = cache_path(AUDIO_FORMAT);
+        let mut target_path = vec![(video, v_path.clone())];
+        let a_path = match selection.audio_url {
+            Some(a_url) => {
+                let audio = Arc::new(Mirrors::new(a_url, selection.audio_backup));
+                target_path.push((audio, a_path.clone()));
+                Some(a_path)
+            }
+            None => None,
+        };
+        let refresher = Arc::new(refresher.streams(target_path.iter().map(|(m, _)| m.clone())));
+        self.process.set_phase(Phase::Downloading);
+        if !self.download(target_path, refresher).await? {
+            return Err(Error::Cancelled);
+        }
+        let paths: Vec<String> = std::iter::once(v_path.clone())
+            .chain(a_path.clone())
+            .collect();
+        for path in &paths {
+            self.check_stream(path).await?;
+        }
+        let out_path = self.out_path(&title);
+        self.process.set_phase(Phase::Merging);
+        helper::merge(v_path, a_path, out_path.clone()).await?;
+        if verify() {
+            self.check_merged(out_path, duration, &paths).await?;
+        }
+        if let Err(e) = ARCHIVE.add(key) {
+            println!(
+                "Failed to add task {} to the download archive: {e}",
+                self.id
+            );
+        }
+        Ok(())
     }
 
     /// A helper function for `Task::execute()`
     /// # Args
-    /// `target_path` is in the form of [(targte, path)]
-    /// `target`: A direct download url
+    /// `target_path` is in the form of [(mirrors, path)]
+    /// `mirrors`: The direct download urls of a stream
     /// `path`: Ends with `VIDEO/AUDIO_FORMAT'
-    async fn download(&self, target_path: Vec<(String, String)>) -> TaskResult<bool> {
+    /// `refresher`: Gets fresh urls of the streams when they expire
+    /// Fails with `Error::InsufficientSpace` before downloading if the disk is too full,
+    /// otherwise the cache files are allocated in full
+    async fn download(
+        &self,
+        target_path: Vec<(Arc<Mirrors>, String)>,
+        refresher: Arc<Refresher>,
+    ) -> TaskResult<bool> {
         let mut handles = JoinSet::new();
-        for (target, path) in target_path {
-            let total = Self::get_content_length(target.as_str()).await?;
-            self.process.add_total(total);
-            let headers_gen = Arc::new(HeadersGen::new(0, total));
-            let file = Arc::new(helper::fs_open(&path).await);
-            for _ in 0..*PARTS.get().unwrap() {
-                let headers_gen_c = headers_gen.clone();
-                let file_c = file.clone();
+        if let Ok(mut in_use) = self.mirrors.lock() {
+            in_use.clear();
+        }
+        let mut streams = Vec::new();
+        for (mirrors, path) in target_path {
+            let total = Self::get_content_length(&self.client, &mirrors).await?;
+            streams.push((mirrors, path, total));
+        }
+        let sizes: Vec<(&str, usize)> = streams
+            .iter()
+            .map(|(_, path, total)| (path.as_str(), *total))
+            .collect();
+        check_space(&self.cache_dir(), &sizes)?;
+        for (mirrors, path, total) in streams {
+            let mut holes = Vec::new();
+            self.update_manifest(|m| {
+                m.open_stream(&path, &mirrors.primary(), total);
+                holes = m.stream(&path).map_or_else(Vec::new, |s| s.holes());
+            })
+            .await?;
+            if let Ok(mut in_use) = self.mirrors.lock() {
+                in_use.push(mirrors.clone());
+            }
+            let left: usize = holes.iter().map(|(start, end)| end - start + 1).sum();
+            let name = match path.ends_with(AUDIO_FORMAT) {
+                true => "audio",
+                false => "video",
+            };
+            let count = self.process.add_stream(name, total, total - left);
+            // the space checked might have been taken by another task since
+            let file = helper::fs_open(&path).map_err(|e| disk_error(e, &path, total as u64))?;
+            helper::preallocate(&file, total as u64)
+                .map_err(|e| disk_error(e, &path, total as u64))?;
+            let stream = Arc::new(StreamJob {
+                file: Arc::new(file),
+                mirrors,
+                path,
+                count,
+                headers_gen: HeadersGen::skipping(holes, self.parts),
+            });
+            for _ in 0..self.parts {
                 handles.spawn(Self::download_range(
-                    file_c,
-                    target.to_owned(),
-                    headers_gen_c,
+                    self.client.clone(),
+                    self.limits.clone(),
+                    stream.clone(),
+                    refresher.clone(),
+                    self.manifest.clone(),
                     self.process.clone(),
                     self.fsm.clone(),
                 ));
             }
         }
-        let mut res = true;
-        for _ in 0..handles.len() {
-            res &= handles.join_next().await.unwrap()??;
-            if !res {
-                break;
+        let res = self.join_ranges(&mut handles).await;
+        // what was recorded since the last save, however it ended
+        self.save_manifest().await?;
+        res
+    } // JoinHandles are dropped with JoinSet here
+
+    /// Wait for the workers, saving what they recorded every `SAVE_INTERVAL`
+    async fn join_ranges(&self, handles: &mut JoinSet<TaskResult<bool>>) -> TaskResult<bool> {
+        let mut ticker = tokio::time::interval(SAVE_INTERVAL);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                res = handles.join_next() => match res {
+                    Some(res) => if !res?? {
+                        return Ok(false);
+                    },
+                    None => return Ok(true),
+                },
+                _ = ticker.tick() => self.save_manifest().await?,
             }
         }
-        Ok(res)
-    } // JoinHandles are dropped with JoinSet here
+    }
+
+    /// Every byte of the stream at `path` must be recorded and on the disk.
+    /// Otherwise its chunks are forgotten, so that a retry downloads it again
+    async fn check_stream(&self, path: &str) -> TaskResult<()> {
+        let (total, holes) = {
+            let manifest = self.manifest.lock().map_err(|e| Error::Io(e.to_string()))?;
+            let stream = manifest
+                .stream(path)
+                .ok_or_else(|| Error::Corrupted(format!("{path} is not recorded")))?;
+            (stream.total, stream.holes())
+        };
+        let size = std::fs::metadata(path)?.len() as usize;
+        let problem = match holes.first() {
+            Some((start, end)) => Some(format!(
+                "{} holes in {path}, the first at {start}-{end}",
+                holes.len()
+            )),
+            None if size != total => Some(format!("{path} is {size} bytes, not {total}")),
+            None => None,
+        };
+        match problem {
+            Some(problem) => {
+                self.update_manifest(|m| m.forget(path)).await?;
+                Err(Error::Corrupted(problem))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Check the mp4 merged from `paths`, remove it and forget the streams if it's broken
+    async fn check_merged(
+        &self,
+        out_path: String,
+        duration: Option<f64>,
+        paths: &[String],
+    ) -> TaskResult<()> {
+        let path = out_path.clone();
+        let res =
+            tokio::task::spawn_blocking(move || crate::verify::check_mp4(path, duration)).await?;
+        if res.is_err() {
+            let _ = std::fs::remove_file(&out_path);
+            self.update_manifest(|m| paths.iter().for_each(|path| m.forget(path)))
+                .await?;
+        }
+        res
+    }
+
+    fn out_path(&self, title: &str) -> String {
+        format!("{}/{title}.{VIDEO_FORMAT}", self.save_dir)
+    }
+
+    /// What the task journal keeps
+    pub fn record(&self) -> TaskRecord {
+        let state = self.fsm.now();
+        let title = self.title();
+        TaskRecord {
+            id: self.id,
+            target: self.target.to_owned(),
+            path: match state {
+                State::Finished => self.out_path(&title),
+                _ => String::new(),
+            },
+            title,
+            size: self.process.total(),
+            state: state.code(),
+            created: self.created,
+            updated: store::now(),
+            error: self.error(),
+            skipped: self.skipped(),
+        }
+    }
+
+    pub(crate) fn save_record(&self) {
+        if let Err(e) = store::JOURNAL.append(&self.record()) {
+            println!("Failed to record task {}: {e}", self.id);
+        }
+    }
+
+    fn cache_dir(&self) -> std::path::PathBuf {
+        manifest::cache_dir(&self.save_dir, self.id)
+    }
+
+    /// Change the manifest and save it in the cache dir
+    async fn update_manifest<F: FnOnce(&mut Manifest)>(&self, f: F) -> TaskResult<()> {
+        {
+            let mut manifest = self.manifest.lock().map_err(|e| Error::Io(e.to_string()))?;
+            f(&mut manifest);
+        }
+        self.save_manifest().await
+    }
+
+    /// Save a copy of the manifest off the runtime, the workers go on recording meanwhile
+    async fn save_manifest(&self) -> TaskResult<()> {
+        let manifest = self
+            .manifest
+            .lock()
+            .map_err(|e| Error::Io(e.to_string()))?
+            .clone();
+        let cache_dir = self.cache_dir();
+        tokio::task::spawn_blocking(move || manifest.save(cache_dir)).await?
+    }
 
+    #[allow(clippy::too_many_arguments)]
     async fn download_range(
-        file: Arc<tokio::sync::RwLock<tokio::io::BufWriter<tokio::fs::File>>>,
-        target: String,
-        headers_gen: Arc<HeadersGen>,
+        client: Client,
+        limits: Limits,
+        stream: Arc<StreamJob>,
+        refresher: Arc<Refresher>,
+        manifest: Arc<std::sync::Mutex<Manifest>>,
         process: Arc<Process>,
         fsm: Arc<FSM>,
     ) -> TaskResult<bool> {
-        let client = Client::new();
+        let read_timeout = tokio::time::Duration::from_secs(http().read_timeout);
+        let policy = retry();
+        let StreamJob {
+            file,
+            mirrors,
+            path,
+            count,
+            headers_gen,
+        } = &*stream;
         let res = loop {
             tokio::select! {
-                Some(mut headers) = async { headers_gen.next() }, if fsm.now_state_code() == 0 => {
-                    let mut offset = headers.get("Range").unwrap().to_str().unwrap().split('-').next().unwrap().split('=').last().unwrap().parse::<u64>().unwrap();
-                    let mut file = file.write().await;
-                    let mut resp = helper::get_resp(&client, &target, &headers).await;
-                    file.seek(SeekFrom::Start(offset)).await.unwrap();
+                Some((id, mut headers)) = async { headers_gen.next() }, if fsm.now() == State::Working => {
+                    let (start, mut to) = range_of(&headers)?;
+                    let begun = tokio::time::Instant::now();
+                    let (mut mirror, mut resp) = refresher.request(&client, mirrors, &headers, &policy).await?;
+                    let _connection = process.connect();
+                    // where `buf` goes in the file
+                    let mut offset = start;
+                    let mut buf = Vec::with_capacity(WRITE_SIZE);
+                    // waiting on the network, not on the rate limits
+                    let mut waited = tokio::time::Duration::ZERO;
+                    // breaks in a row without getting anything
+                    let mut attempt = 0;
+                    let mut retries = 0;
                     loop {
-                        let gotten = resp.chunk().await;
-                        match gotten {
+                        let now = tokio::time::Instant::now();
+                        // a stalled connection is requested again like a broken one
+                        let gotten = tokio::time::timeout(read_timeout, resp.chunk())
+                            .await
+                            .map_err(drop)
+                            .and_then(|chunk| chunk.map_err(drop));
+                        waited += now.elapsed();
+                        // the next byte to arrive
+                        let received = offset + buf.len() as u64;
+                        let broke = match gotten {
                             Ok(Some(chunk)) => {
-                                let size = chunk.len();
-                                file.write_all(&chunk).await.unwrap();
-                                process.add_finished(size);
+                                attempt = 0;
+                                // the bytes past a shortened end are left to the worker taking over
+                                to = headers_gen
+                                    .progress(id, received as usize + chunk.len())
+                                    .map_or(to, |end| end as u64);
+                                let kept = chunk.len().min((to + 1).saturating_sub(received) as usize);
+                                limits.acquire(kept).await;
+                                buf.extend_from_slice(&chunk[..kept]);
+                                process.add_finished(count, kept);
+                                if !limits.limited() {
+                                    mirrors.measure(mirror, kept);
+                                }
+                                false
                             },
-                            Ok(None) => {break;},
-                            Err(_) => {
-                                println!("retry");
-                                offset = file.seek(SeekFrom::Current(0)).await.unwrap();
-                                let to = headers.get("Range").unwrap().to_str().unwrap().split('-').last().unwrap().parse::<usize>().unwrap();
-                                headers.insert("Range", format!("bytes={offset}-{to}").parse().unwrap());
-                                resp = helper::get_resp(&client, &target, &headers).await;
-                                // println!("{headers:?}:{}\n", resp.status())
+                            // ended before the range did
+                            Ok(None) => received <= to,
+                            Err(_) => true,
+                        };
+                        let done = !broke && offset + buf.len() as u64 > to;
+                        // write out before the chunk is recorded or the range is requested again
+                        if done || broke || buf.len() >= WRITE_SIZE {
+                            offset = flush_at(file, path, &mut buf, offset).await?;
+                        }
+                        if done {
+                            headers_gen.finish(id, (to + 1 - start) as usize, begun.elapsed());
+                            process.add_timing(RangeTiming {
+                                stream: std::path::Path::new(path)
+                                    .file_name()
+                                    .map_or_else(|| path.to_owned(), |f| f.to_string_lossy().into_owned()),
+                                start: start as usize,
+                                end: to as usize,
+                                elapsed: begun.elapsed().as_millis() as u64,
+                                waited: waited.as_millis() as u64,
+                                retries,
+                                mirror: mirrors::host(&mirrors.current().1).to_owned(),
+                            });
+                            // saved by `Task::download` every now and then
+                            if let Ok(mut manifest) = manifest.lock() {
+                                manifest.record(path, (start as usize, to as usize));
                             }
+                            break;
+                        }
+                        if broke {
+                            attempt += 1;
+                            retries += 1;
+                            process.add_retry();
+                            if attempt > policy.max_attempts {
+                                return Err(Error::Network(format!(
+                                    "bytes {offset}-{to} of {} broke off {attempt} times",
+                                    mirrors::host(&mirrors.current().1)
+                                )));
+                            }
+                            mirrors.fail(mirror);
+                            tokio::time::sleep(policy.delay(attempt)).await;
+                            to = headers_gen.progress(id, offset as usize).map_or(to, |end| end as u64);
+                            let range = header::HeaderValue::from_str(&format!("bytes={offset}-{to}"))
+                                .map_err(|e| Error::ParseFailed(e.to_string()))?;
+                            headers.insert(header::RANGE, range);
+                            (mirror, resp) = refresher.request(&client, mirrors, &headers, &policy).await?;
                         }
                     }
                 }
-                _ = async {}, if fsm.now_state_code() != 0 => {
-                    let state_code = fsm.now_state_code();
-                    match state_code {
-                        2 => break false,
-                        1 => {tokio::time::sleep(tokio::time::Duration::from_secs(1)).await},
-                        _ => unreachable!(),
+                _ = async {}, if fsm.now() != State::Working => {
+                    match fsm.now() {
+                        // queued again by a retry before this run stopped, it goes on when started
+                        State::Pausing | State::Queued => {tokio::time::sleep(tokio::time::Duration::from_secs(1)).await},
+                        State::Working => {},
+                        _ => break false,
                     }
                 }
                 // true if no branch match, means succeed finishing
@@ -177,38 +659,93 @@ impl Task {
     }
 
     pub fn switch(&self) {
-        self.fsm.switch();
+        self.transition(FSM::switch);
+    }
+
+    /// Share the global limiter of the executor
+    pub(crate) fn limit_by(&mut self, global: Arc<RateLimiter>) {
+        self.limits.global = global;
+    }
+
+    /// Send the events to the subscribers of the executor
+    pub(crate) fn notify(&mut self, events: Events) {
+        self.events = events;
+    }
+
+    /// How many connections it may open, `PARTS` for the video and the audio
+    pub fn connections(&self) -> usize {
+        2 * self.parts
+    }
+
+    /// Cap the speed of this task in bytes per second, `None` is unlimited.
+    /// Takes effect while downloading
+    pub fn set_rate_limit(&self, rate: Option<u64>) {
+        self.limits.task.set_rate(rate);
     }
 
     pub fn cancel(&self) {
-        self.fsm.cancel();
+        self.transition(FSM::cancel);
     }
 
-    async fn get_content_length(target: &str) -> TaskResult<usize> {
-        let resp = Client::new()
-            .get(target)
-            .header(header::USER_AGENT, USER_AGENT)
-            .header(header::REFERER, "https://www.bilibili.com/")
-            .header(header::RANGE, "bytes=0-0")
-            .send()
-            .await?;
-        let hd = resp.headers();
-        dbg!(&hd);
-        let length = hd
-            .get("content-range")
-            .unwrap_or(&header::HeaderValue::from_str(&format!("/{}", usize::MAX)).unwrap())
-            .to_str()
-            .unwrap()
-            .split('/')
-            .last()
-            .unwrap()
-            .parse::<usize>()
-            .unwrap();
-        Ok(length)
+    /// Move a failed or cancelled task back to the queue,
+    /// return true if it should be executed again
+    pub fn retry(&self) -> bool {
+        if !self.transition(FSM::retry) {
+            return false;
+        }
+        if let Ok(mut error) = self.error.lock() {
+            *error = None;
+        }
+        true
+    }
+
+    /// Ask the mirrors in turn until one answers the range,
+    /// the ones ignoring it can't be downloaded in parts
+    async fn get_content_length(client: &Client, mirrors: &Mirrors) -> TaskResult<usize> {
+        let mut tried = 0;
+        let resp = loop {
+            let (index, target) = mirrors.current();
+            let resp = client
+                .get(&target)
+                .header(header::USER_AGENT, USER_AGENT)
+                .header(header::REFERER, "https://www.bilibili.com/")
+                .header(header::RANGE, "bytes=0-0")
+                .send()
+                .await
+                .and_then(|resp| resp.error_for_status());
+            tried += 1;
+            let moved_on = || tried < mirrors.len() && mirrors.rotate(index);
+            match resp {
+                Ok(resp) if resp.status() == reqwest::StatusCode::PARTIAL_CONTENT => break resp,
+                Ok(_) if moved_on() => continue,
+                Ok(_) => {
+                    return Err(Error::Network(format!(
+                        "{} ignored the range",
+                        mirrors::host(&target)
+                    )))
+                }
+                Err(_) if moved_on() => continue,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        match resp.headers().get(header::CONTENT_RANGE) {
+            Some(range) => range
+                .to_str()
+                .ok()
+                .and_then(|range| range.rsplit('/').next())
+                .and_then(|total| total.parse::<usize>().ok())
+                .ok_or_else(|| Error::ParseFailed(format!("invalid content-range {range:?}"))),
+            None => Err(Error::ParseFailed(
+                "unknown length of the stream".to_owned(),
+            )),
+        }
     }
 
     fn rm_cache(&self) {
-        helper::rm_cache(format!("{}/cache_{}/", self.save_dir, self.id));
+        let cache_dir = self.cache_dir();
+        if let Err(e) = helper::rm_cache(&cache_dir) {
+            println!("Failed to remove {}: {e}", cache_dir.display());
+        }
     }
 
     pub fn title(&self) -> String {
@@ -218,18 +755,532 @@ impl Task {
         }
     }
 
+    /// The chosen stream, such as `1080P60 HEVC`
+    pub fn quality(&self) -> String {
+        match self.quality.try_lock() {
+            Ok(quality) => quality.borrow().to_owned(),
+            Err(_) => String::new(),
+        }
+    }
+
     pub fn process(&self) -> String {
         self.process.get()
     }
 
+    /// The bytes, speed and phase of the task
+    pub fn progress(&self) -> Progress {
+        self.process.progress()
+    }
+
+    /// How the finished ranges went, the latest last
+    pub fn range_timings(&self) -> Vec<RangeTiming> {
+        self.process.timings()
+    }
+
+    /// The hosts in use, one for each stream being downloaded
+    pub fn mirrors(&self) -> Vec<String> {
+        self.mirrors.lock().map_or_else(
+            |_| Vec::new(),
+            |in_use| {
+                in_use
+                    .iter()
+                    .map(|m| mirrors::host(&m.current().1).to_owned())
+                    .collect()
+            },
+        )
+    }
+
     pub fn state(&self) -> usize {
         self.fsm.now_state_code()
     }
+
+    pub(crate) fn now(&self) -> State {
+        self.fsm.now()
+    }
+
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    /// Why the task failed or got cancelled, `None` if it didn't
+    pub fn error(&self) -> Option<Error> {
+        self.error.lock().ok()?.clone()
+    }
+
+    /// Finished by the download archive instead of downloading
+    pub fn skipped(&self) -> bool {
+        self.skipped.load(Ordering::Relaxed)
+    }
+}
+
+/// Parse a video page
+/// Return the streams and video data found in `__playinfo__` and `__INITIAL_STATE__`,
+/// or in the pgc apis for bangumi
+pub(crate) async fn parse(client: &Client, target: &str) -> TaskResult<ParsedMedia> {
+    if let Some(pgc) = PgcTarget::from_url(target) {
+        return bangumi::parse(client, pgc).await;
+    }
+    let html = helper::get_html(client, target).await?;
+    ParsedMedia::from_html(&html)
+}
+
+/// The rest of the streams `(path, total)` and the file merged from them
+/// must fit on the disk of `cache_dir`, the save dir is on the same one
+fn check_space(cache_dir: &std::path::Path, streams: &[(&str, usize)]) -> TaskResult<()> {
+    let needed = streams
+        .iter()
+        .map(|(path, total)| {
+            // allocated by the last run
+            let cached = std::fs::metadata(path).map_or(0, |m| m.len());
+            (*total as u64).saturating_sub(cached) + *total as u64
+        })
+        .sum();
+    let available = helper::available_space(cache_dir)?;
+    match needed > available {
+        true => Err(Error::InsufficientSpace { needed, available }),
+        false => Ok(()),
+    }
+}
+
+/// `Error::InsufficientSpace` if writing `needed` bytes at `path` found the disk full
+fn disk_error(e: std::io::Error, path: &str, needed: u64) -> Error {
+    match helper::disk_full(&e) {
+        true => Error::InsufficientSpace {
+            needed,
+            available: helper::available_space(path).unwrap_or(0),
+        },
+        false => e.into(),
+    }
+}
+
+/// What the range workers of a stream share
+#[derive(Debug)]
+struct StreamJob {
+    file: Arc<std::fs::File>,
+    mirrors: Arc<Mirrors>,
+    path: String,
+    count: Arc<StreamCount>,
+    headers_gen: HeadersGen,
+}
+
+/// Parses the target again for fresh stream urls when the signed ones expire,
+/// shared by the range workers of a task
+#[derive(Debug)]
+struct Refresher {
+    client: Client,
+    target: String,
+    policy: QualityPolicy,
+    // the fresh stream must be the one chosen before
+    quality: u32,
+    codec: Option<Codec>,
+    // video, then audio
+    streams: Vec<Arc<Mirrors>>,
+    // one worker refreshes at a time
+    refreshing: Mutex<()>,
+    // the refreshes since the urls last answered
+    refreshed: AtomicU32,
+}
+
+impl Refresher {
+    fn new(client: &Client, target: &str, policy: &QualityPolicy, selection: &Selection) -> Self {
+        Self {
+            client: client.clone(),
+            target: target.to_owned(),
+            policy: policy.to_owned(),
+            quality: selection.quality,
+            codec: selection.codec,
+            streams: Vec::new(),
+            refreshing: Mutex::new(()),
+            refreshed: AtomicU32::new(0),
+        }
+    }
+
+    fn streams(self, streams: impl Iterator<Item = Arc<Mirrors>>) -> Self {
+        Self {
+            streams: streams.collect(),
+            ..self
+        }
+    }
+
+    /// `helper::get_resp`, going on with fresh urls if they have expired
+    async fn request(
+        &self,
+        client: &Client,
+        mirrors: &Mirrors,
+        headers: &header::HeaderMap,
+        policy: &RetryPolicy,
+    ) -> TaskResult<(usize, reqwest::Response)> {
+        loop {
+            let generation = mirrors.generation();
+            match helper::get_resp(client, mirrors, headers, policy).await {
+                Err(Error::UrlExpired) => self.refresh(mirrors, generation, policy).await?,
+                Ok(resp) => {
+                    // the limit is of each expiry
+                    self.refreshed.store(0, Ordering::Relaxed);
+                    return Ok(resp);
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Replace the urls of every stream, unless another worker has done it since `generation`
+    async fn refresh(
+        &self,
+        mirrors: &Mirrors,
+        generation: usize,
+        policy: &RetryPolicy,
+    ) -> TaskResult<()> {
+        let _refreshing = self.refreshing.lock().await;
+        if mirrors.generation() != generation {
+            return Ok(());
+        }
+        if self.refreshed.fetch_add(1, Ordering::Relaxed) >= policy.max_attempts {
+            return Err(Error::UrlExpired);
+        }
+        println!("Refreshing the stream urls of {}", self.target);
+        let media = parse(&self.client, &self.target).await?;
+        let selection = media
+            .select(&self.policy)
+            .filter(|s| s.quality == self.quality && s.codec == self.codec)
+            .ok_or(Error::UrlExpired)?;
+        let fresh = [
+            Some((selection.video_url, selection.video_backup)),
+            selection.audio_url.map(|url| (url, selection.audio_backup)),
+        ];
+        for (stream, (primary, backup)) in self.streams.iter().zip(fresh.into_iter().flatten()) {
+            stream.replace(primary, backup);
+        }
+        Ok(())
+    }
+}
+
+/// The `(start, end)` in the `Range` header made by `HeadersGen`
+fn range_of(headers: &header::HeaderMap) -> TaskResult<(u64, u64)> {
+    headers
+        .get(header::RANGE)
+        .and_then(|range| range.to_str().ok())
+        .and_then(|range| range.strip_prefix("bytes="))
+        .and_then(|range| range.split_once('-'))
+        .and_then(|(start, end)| Some((start.parse().ok()?, end.parse().ok()?)))
+        .ok_or_else(|| Error::ParseFailed(format!("invalid range in {headers:?}")))
+}
+
+/// Write out `buf` at `offset`, return where the next bytes go
+async fn flush_at(
+    file: &Arc<std::fs::File>,
+    path: &str,
+    buf: &mut Vec<u8>,
+    offset: u64,
+) -> TaskResult<u64> {
+    if buf.is_empty() {
+        return Ok(offset);
+    }
+    let len = buf.len() as u64;
+    helper::write_at(
+        file.clone(),
+        std::mem::replace(buf, Vec::with_capacity(WRITE_SIZE)),
+        offset,
+    )
+    .await
+    .map_err(|e| disk_error(e, path, len))?;
+    Ok(offset + len)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::headers::MIN_CHUNK;
+    use std::io::{BufRead, BufReader, Write};
+    use std::time::{Duration, Instant};
+
+    fn byte_at(i: usize) -> u8 {
+        (i % 251) as u8
+    }
+
+    /// A local HTTP server answering `Range` requests for `total` bytes,
+    /// each connection sends at most `rate` bytes per second if given.
+    /// Paths under `/forbidden` get 403 like an expired mirror, under `/unavailable` 503
+    fn serve(total: usize, rate: Option<usize>) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                std::thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut stream = stream;
+                    // keep-alive, one request after another
+                    loop {
+                        let mut range = (0, total - 1);
+                        let mut forbidden = false;
+                        let mut unavailable = false;
+                        // answers every range with the whole stream
+                        let mut whole = false;
+                        let mut line = String::new();
+                        loop {
+                            line.clear();
+                            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                                return;
+                            }
+                            if line == "\r\n" {
+                                break;
+                            }
+                            forbidden |= line.contains(" /forbidden");
+                            unavailable |= line.contains(" /unavailable");
+                            whole |= line.contains(" /whole");
+                            let lower = line.to_lowercase();
+                            if let Some((start, end)) = lower
+                                .strip_prefix("range: bytes=")
+                                .and_then(|r| r.trim().split_once('-'))
+                            {
+                                range = (start.parse().unwrap(), end.parse().unwrap());
+                            }
+                        }
+                        if forbidden || unavailable {
+                            let head = match forbidden {
+                                true => "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n",
+                                false => {
+                                    "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n"
+                                }
+                            };
+                            if stream.write_all(head.as_bytes()).is_err() {
+                                return;
+                            }
+                            continue;
+                        }
+                        let (start, end) = match whole {
+                            true => (0, total - 1),
+                            false => (range.0, std::cmp::min(range.1, total - 1)),
+                        };
+                        let head = match whole {
+                            true => format!("HTTP/1.1 200 OK\r\nContent-Length: {total}\r\n\r\n"),
+                            false => format!(
+                                "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nContent-Range: bytes {start}-{end}/{total}\r\n\r\n",
+                                end - start + 1
+                            ),
+                        };
+                        if stream.write_all(head.as_bytes()).is_err() {
+                            return;
+                        }
+                        let body: Vec<u8> = (start..=end).map(byte_at).collect();
+                        let piece = rate.map_or(body.len(), |rate| rate / 100);
+                        for piece in body.chunks(piece.max(1)) {
+                            if stream.write_all(piece).is_err() {
+                                return;
+                            }
+                            if rate.is_some() {
+                                std::thread::sleep(Duration::from_millis(10));
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        format!("http://{addr}")
+    }
+
+    /// What `Task::download` does for a stream, without the parsing
+    async fn fetch(mirrors: Mirrors, path: &str, parts: usize) -> usize {
+        let client = helper::client(&http());
+        let total = Task::get_content_length(&client, &mirrors).await.unwrap();
+        let manifest = Arc::new(std::sync::Mutex::new(Manifest::default()));
+        manifest
+            .lock()
+            .unwrap()
+            .open_stream(path, &mirrors.primary(), total);
+        let process = Arc::new(Process::new());
+        let stream = Arc::new(StreamJob {
+            file: Arc::new(helper::fs_open(path).unwrap()),
+            mirrors: Arc::new(mirrors),
+            path: path.to_owned(),
+            count: process.add_stream("video", total, 0),
+            headers_gen: HeadersGen::new(total, parts),
+        });
+        // never used, the urls don't expire
+        let refresher = Arc::new(Refresher::new(
+            &client,
+            "BV1Ao4y1b7fj",
+            &QualityPolicy::default(),
+            &Selection::default(),
+        ));
+        let fsm = Arc::new(FSM::new());
+        fsm.start();
+        let mut handles = JoinSet::new();
+        for _ in 0..parts {
+            handles.spawn(Task::download_range(
+                client.clone(),
+                Limits::default(),
+                stream.clone(),
+                refresher.clone(),
+                manifest.clone(),
+                process.clone(),
+                fsm.clone(),
+            ));
+        }
+        while let Some(res) = handles.join_next().await {
+            assert!(res.unwrap().unwrap());
+        }
+        assert_eq!(process.finished(), total);
+        let progress = process.progress();
+        assert_eq!(progress.streams[0].downloaded, total);
+        assert_eq!(progress.connections, 0);
+        let timed: usize = process.timings().iter().map(|t| t.end - t.start + 1).sum();
+        assert_eq!(timed, total);
+        total
+    }
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn download_local() {
+        let total = MIN_CHUNK * 10 + WRITE_SIZE / 2;
+        let server = serve(total, None);
+        // the primary mirror is rejected, the second ignores the ranges, the last is used
+        let mirrors = Mirrors::new(
+            format!("{server}/forbidden/video.m4s"),
+            vec![
+                format!("{server}/whole/video.m4s"),
+                format!("{server}/video.m4s"),
+            ],
+        );
+        let dir = std::env::temp_dir().join("bili_download_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("video.m4s");
+        runtime().block_on(fetch(mirrors, path.to_str().unwrap(), 3));
+        let written = std::fs::read(&path).unwrap();
+        assert_eq!(written.len(), total);
+        assert!(written.iter().enumerate().all(|(i, b)| *b == byte_at(i)));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn give_up() {
+        let server = serve(10, None);
+        let client = helper::client(&http());
+        let headers = header::HeaderMap::new();
+        let policy = RetryPolicy {
+            max_attempts: 2,
+            base_delay: 1,
+            ..Default::default()
+        };
+        runtime().block_on(async {
+            // every mirror says 403
+            let mirrors = Mirrors::new(
+                format!("{server}/forbidden/1"),
+                vec![format!("{server}/forbidden/2")],
+            );
+            let res = helper::get_resp(&client, &mirrors, &headers, &policy).await;
+            assert_eq!(res.unwrap_err(), Error::UrlExpired);
+            // past the deadline, not even requested
+            let mirrors = Mirrors::new(format!("{server}/video?deadline=1"), Vec::new());
+            let res = helper::get_resp(&client, &mirrors, &headers, &policy).await;
+            assert_eq!(res.unwrap_err(), Error::UrlExpired);
+            // retried, then given up
+            let mirrors = Mirrors::new(format!("{server}/unavailable"), Vec::new());
+            let res = helper::get_resp(&client, &mirrors, &headers, &policy).await;
+            assert!(matches!(res, Err(Error::Network(_))));
+            // the whole stream for a range is refused
+            let mut ranged = header::HeaderMap::new();
+            ranged.insert(header::RANGE, header::HeaderValue::from_static("bytes=2-5"));
+            let mirrors = Mirrors::new(format!("{server}/whole"), Vec::new());
+            let res = helper::get_resp(&client, &mirrors, &ranged, &policy).await;
+            assert!(matches!(res, Err(Error::Network(_))));
+            assert!(Task::get_content_length(&client, &mirrors).await.is_err());
+        });
+    }
+
+    /// Run with `cargo test --release -- --ignored bench_parts --nocapture`,
+    /// every connection is limited to 8MB/s as the CDN does, so that more parts are faster
+    #[test]
+    #[ignore]
+    fn bench_parts() {
+        let total = 40_000_000;
+        let server = serve(total, Some(8_000_000));
+        let dir = std::env::temp_dir().join("bili_bench_parts");
+        std::fs::create_dir_all(&dir).unwrap();
+        for parts in [1, 2, 4, 8] {
+            let path = dir.join(format!("video_{parts}.m4s"));
+            let _ = std::fs::remove_file(&path);
+            let now = Instant::now();
+            let mirrors = Mirrors::new(format!("{server}/video.m4s"), Vec::new());
+            runtime().block_on(fetch(mirrors, path.to_str().unwrap(), parts));
+            let secs = now.elapsed().as_secs_f64();
+            println!(
+                "parts {parts}: {secs:.2}s, {:.1}MB/s",
+                total as f64 / secs / 1_000_000.0
+            );
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn parse_range() {
+        let mut headers = header::HeaderMap::new();
+        headers.insert(header::RANGE, "bytes=5000001-10000000".parse().unwrap());
+        assert_eq!(range_of(&headers).unwrap(), (5000001, 10000000));
+        headers.insert(header::RANGE, "bytes=5-".parse().unwrap());
+        assert!(matches!(range_of(&headers), Err(Error::ParseFailed(_))));
+    }
+
+    #[test]
+    fn free_space() {
+        let dir = std::env::temp_dir().join("bili_space_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("video.m4s");
+        let path = path.to_str().unwrap();
+        check_space(&dir, &[(path, 1_000_000)]).unwrap();
+        // an allocated stream only needs room for the merged file
+        std::fs::write(path, vec![0; 1_000_000]).unwrap();
+        check_space(&dir, &[(path, 1_000_000)]).unwrap();
+        let res = check_space(&dir, &[(path, usize::MAX / 4)]);
+        assert!(matches!(res, Err(Error::InsufficientSpace { .. })));
+        // filled up by another task after the check
+        #[cfg(unix)]
+        {
+            let full = std::io::Error::from_raw_os_error(libc::ENOSPC);
+            let res = disk_error(full, path, 1_000_000);
+            assert!(matches!(
+                res,
+                Error::InsufficientSpace {
+                    needed: 1_000_000,
+                    ..
+                }
+            ));
+            let denied = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+            assert!(matches!(disk_error(denied, path, 1), Error::Io(_)));
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn state_events() {
+        let events = Events::default();
+        let mut sub = events.subscribe();
+        let _ = SAVE_PATH.set(std::env::temp_dir().to_str().unwrap().to_owned());
+        let mut task = Task::new(7, "BV1xx411c7mD".to_owned(), TaskOptions::default());
+        task.notify(events);
+        // a queued task can't be paused
+        task.switch();
+        task.cancel();
+        // already cancelled, nothing changes
+        task.cancel();
+        assert!(task.retry());
+        drop(task);
+        let mut states = Vec::new();
+        while let Some(event) = sub.blocking_recv() {
+            let Event::StateChanged { id: 7, state } = event else {
+                panic!("{event:?}");
+            };
+            states.push(state);
+        }
+        assert_eq!(states, [2, 5]);
+    }
 
     #[test]
     fn test_get_content_length() {
@@ -240,7 +1291,10 @@ mod test {
         rt.block_on(async {
             let target = String::from("https://xy139x226x24x92xy.mcdn.bilivideo.cn:8082/v1/resource/1181828689-1-100110.m4s?agrr=0&build=0&buvid=&bvc=vod&bw=29918&cdnid=71704&deadline=1688362382&e=ig8euxZM2rNcNbdlhoNvNC8BqJIzNbfqXBvEqxTEto8BTrNvN0GvT90W5JZMkX_YN0MvXg8gNEV4NC8xNEV4N03eN0B5tZlqNxTEto8BTrNvNeZVuJ10Kj_g2UB02J0mN0B5tZlqNCNEto8BTrNvNC7MTX502C8f2jmMQJ6mqF2fka1mqx6gqj0eN0B599M%3D&gen=playurlv2&logo=80000000&mid=0&nbs=1&nettype=0&oi=2073295812&orderid=0%2C3&os=bcache&platform=pc&sign=de24e3&traceid=trosoULGtgptRC_0_e_N&uipk=5&uparams=e%2Cuipk%2Cnbs%2Cdeadline%2Cgen%2Cos%2Coi%2Ctrid%2Cmid%2Cplatform&upsig=2adf885b104fbd37e096b22dccb491c0");
             // let target = String::from("https://cn-jstz-cu-01-04.bilivideo.com/upgcxcode/89/86/1181828689/1181828689_nb3-1-30080.m4s?e=ig8euxZM2rNcNbdlhoNvNC8BqJIzNbfqXBvEqxTEto8BTrNvN0GvT90W5JZMkX_YN0MvXg8gNEV4NC8xNEV4N03eN0B5tZlqNxTEto8BTrNvNeZVuJ10Kj_g2UB02J0mN0B5tZlqNCNEto8BTrNvNC7MTX502C8f2jmMQJ6mqF2fka1mqx6gqj0eN0B599M=&uipk=5&nbs=1&deadline=1688360215&gen=playurlv2&os=bcache&oi=2073295812&trid=0000f176f753d0d145fcb3955155bed6c30eu&mid=32280488&platform=pc&upsig=ed7ade91c9210ac3fef1f0683b1123fe&uparams=e,uipk,nbs,deadline,gen,os,oi,trid,mid,platform&cdnid=71704&bvc=vod&nettype=0&orderid=0,3&buvid=1FF87ED7-2D57-84BE-3FD0-8F03EC5B47F949754infoc&build=0&agrr=0&bw=124636&logo=80000000");
-            let length = Task::get_content_length(&target).await.unwrap();
+            let client = helper::client(&HttpConfig::default());
+            let length = Task::get_content_length(&client, &Mirrors::new(target, Vec::new()))
+                .await
+                .unwrap();
             dbg!(length);
         })
     }
@@ -0,0 +1,190 @@
+//! The structural check of a merged mp4.
+//! The boxes must cover the whole file, and the tracks must be as long as the video
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::error::Error;
+
+type VerifyResult<T> = Result<T, Error>;
+
+/// Seconds the durations may differ, plus 1% of the video
+const TOLERANCE: f64 = 2.0;
+
+fn corrupt(detail: String) -> Error {
+    Error::Corrupted(detail)
+}
+
+/// `(type, size, header size)` of the box at the start of `buf`, `remaining` bytes are left
+fn header(buf: &[u8], remaining: u64) -> VerifyResult<([u8; 4], u64, u64)> {
+    if buf.len() < 8 {
+        return Err(corrupt(format!("{} stray bytes at the end", buf.len())));
+    }
+    let kind = [buf[4], buf[5], buf[6], buf[7]];
+    let (size, header_size) = match u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) {
+        // to the end
+        0 => (remaining, 8),
+        1 if buf.len() >= 16 => (u64::from_be_bytes(buf[8..16].try_into().unwrap()), 16),
+        1 => return Err(corrupt("a cut off 64-bit box size".to_owned())),
+        size => (size as u64, 8),
+    };
+    if size < header_size || size > remaining {
+        return Err(corrupt(format!(
+            "the {} box of {size} bytes doesn't fit in {remaining}",
+            String::from_utf8_lossy(&kind)
+        )));
+    }
+    Ok((kind, size, header_size))
+}
+
+/// The child boxes filling `data`
+fn children(data: &[u8]) -> VerifyResult<Vec<([u8; 4], &[u8])>> {
+    let mut boxes = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let (kind, size, header_size) = header(&data[offset..], (data.len() - offset) as u64)?;
+        boxes.push((
+            kind,
+            &data[offset + header_size as usize..offset + size as usize],
+        ));
+        offset += size as usize;
+    }
+    Ok(boxes)
+}
+
+fn child<'a>(data: &'a [u8], kind: &[u8; 4]) -> VerifyResult<Option<&'a [u8]>> {
+    Ok(children(data)?
+        .into_iter()
+        .find(|(k, _)| k == kind)
+        .map(|(_, data)| data))
+}
+
+/// In seconds, from the content of a `mvhd` or `mdhd`
+fn duration(data: &[u8]) -> VerifyResult<f64> {
+    let field = |range: std::ops::Range<usize>| {
+        data.get(range)
+            .map(|b| b.iter().fold(0u64, |n, &b| n << 8 | b as u64))
+            .ok_or_else(|| corrupt("a cut off header box".to_owned()))
+    };
+    let (timescale, duration) = match data.first() {
+        Some(1) => (field(20..24)?, field(24..32)?),
+        _ => (field(12..16)?, field(16..20)?),
+    };
+    if timescale == 0 {
+        return Err(corrupt("a timescale of 0".to_owned()));
+    }
+    Ok(duration as f64 / timescale as f64)
+}
+
+/// Check the box tree of the mp4 at `path`,
+/// and that every track lasts as long as the movie and `expected` seconds if given
+pub(crate) fn check_mp4<P: AsRef<Path>>(path: P, expected: Option<f64>) -> VerifyResult<()> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut offset = 0;
+    let mut moov = None;
+    let mut has_media = false;
+    while offset < len {
+        let mut buf = vec![0; (len - offset).min(16) as usize];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut buf)?;
+        let (kind, size, header_size) = header(&buf, len - offset)?;
+        match &kind {
+            b"moov" => {
+                let mut data = vec![0; (size - header_size) as usize];
+                file.seek(SeekFrom::Start(offset + header_size))?;
+                file.read_exact(&mut data)?;
+                moov = Some(data);
+            }
+            b"mdat" | b"moof" => has_media = true,
+            _ => {}
+        }
+        offset += size;
+    }
+    let moov = moov.ok_or_else(|| corrupt("no moov box".to_owned()))?;
+    if !has_media {
+        return Err(corrupt("no mdat box".to_owned()));
+    }
+    let movie = duration(child(&moov, b"mvhd")?.ok_or_else(|| corrupt("no mvhd box".to_owned()))?)?;
+    let tolerance = TOLERANCE + movie / 100.0;
+    let mut tracks = 0;
+    for (_, trak) in children(&moov)?.into_iter().filter(|(k, _)| k == b"trak") {
+        let mdhd = child(trak, b"mdia")?
+            .map(|mdia| child(mdia, b"mdhd"))
+            .transpose()?
+            .flatten()
+            .ok_or_else(|| corrupt("a track without mdhd".to_owned()))?;
+        let track = duration(mdhd)?;
+        if (track - movie).abs() > tolerance {
+            return Err(corrupt(format!(
+                "a track of {track:.1}s in a video of {movie:.1}s"
+            )));
+        }
+        tracks += 1;
+    }
+    if tracks == 0 {
+        return Err(corrupt("no track".to_owned()));
+    }
+    match expected {
+        Some(expected) if (expected - movie).abs() > tolerance => Err(corrupt(format!(
+            "{movie:.1}s long, {expected:.1}s expected"
+        ))),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mp4_box(kind: &[u8; 4], content: &[u8]) -> Vec<u8> {
+        let mut b = ((content.len() + 8) as u32).to_be_bytes().to_vec();
+        b.extend_from_slice(kind);
+        b.extend_from_slice(content);
+        b
+    }
+
+    /// A version 0 `mvhd` or `mdhd`
+    fn header_box(kind: &[u8; 4], timescale: u32, duration: u32) -> Vec<u8> {
+        let mut content = vec![0; 12];
+        content.extend_from_slice(&timescale.to_be_bytes());
+        content.extend_from_slice(&duration.to_be_bytes());
+        content.extend_from_slice(&[0; 8]);
+        mp4_box(kind, &content)
+    }
+
+    fn mp4(tracks: &[(u32, u32)]) -> Vec<u8> {
+        let mut moov = header_box(b"mvhd", 1000, 60_000);
+        for &(timescale, duration) in tracks {
+            let mdia = mp4_box(b"mdia", &header_box(b"mdhd", timescale, duration));
+            moov.extend(mp4_box(b"trak", &mdia));
+        }
+        let mut file = mp4_box(b"ftyp", b"isom\0\0\x02\0");
+        file.extend(mp4_box(b"moov", &moov));
+        file.extend(mp4_box(b"mdat", &[7; 100]));
+        file
+    }
+
+    #[test]
+    fn check() {
+        let path = std::env::temp_dir().join("bili_verify_test.mp4");
+        let video_audio = mp4(&[(90_000, 5_400_000), (44_100, 2_645_000)]);
+        std::fs::write(&path, &video_audio).unwrap();
+        check_mp4(&path, Some(60.0)).unwrap();
+        check_mp4(&path, None).unwrap();
+        assert!(matches!(
+            check_mp4(&path, Some(120.0)),
+            Err(Error::Corrupted(_))
+        ));
+        // cut off in the mdat
+        std::fs::write(&path, &video_audio[..video_audio.len() - 10]).unwrap();
+        assert!(matches!(check_mp4(&path, None), Err(Error::Corrupted(_))));
+        // the audio stops half way
+        std::fs::write(&path, mp4(&[(90_000, 5_400_000), (44_100, 1_323_000)])).unwrap();
+        assert!(matches!(check_mp4(&path, None), Err(Error::Corrupted(_))));
+        std::fs::write(&path, mp4(&[])).unwrap();
+        assert!(matches!(check_mp4(&path, None), Err(Error::Corrupted(_))));
+        std::fs::remove_file(path).unwrap();
+    }
+}
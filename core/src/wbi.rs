@@ -0,0 +1,102 @@
+//! WBI signature, required by some apis such as `x/space/wbi/arc/search`
+
+use serde::Deserialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::Error;
+use crate::helper;
+
+type WbiResult<T> = Result<T, Error>;
+
+const MIXIN_KEY_ENC_TAB: [usize; 64] = [
+    46, 47, 18, 2, 53, 8, 23, 32, 15, 50, 10, 31, 58, 3, 45, 35, 27, 43, 5, 49, 33, 9, 42, 19, 29,
+    28, 14, 39, 12, 38, 41, 13, 37, 48, 7, 16, 24, 55, 40, 61, 26, 17, 0, 1, 60, 51, 30, 4, 22, 25,
+    54, 21, 56, 59, 6, 63, 57, 62, 11, 36, 20, 34, 44, 52,
+];
+
+/// `x/web-interface/nav` returns the keys even if not logged in, with a non-zero code
+#[derive(Deserialize, Debug)]
+struct Nav {
+    data: NavData,
+}
+
+#[derive(Deserialize, Debug)]
+struct NavData {
+    wbi_img: WbiImg,
+}
+
+#[derive(Deserialize, Debug)]
+struct WbiImg {
+    img_url: String,
+    sub_url: String,
+}
+
+/// The stem of the file name in a key url
+fn key_of(url: &str) -> &str {
+    let name = url.rsplit('/').next().unwrap_or(url);
+    name.split('.').next().unwrap_or(name)
+}
+
+fn mixin_key(img_key: &str, sub_key: &str) -> String {
+    let raw: Vec<char> = format!("{img_key}{sub_key}").chars().collect();
+    MIXIN_KEY_ENC_TAB
+        .iter()
+        .filter_map(|&i| raw.get(i))
+        .take(32)
+        .collect()
+}
+
+/// Fetch the mixin key, it changes daily
+pub(crate) async fn fetch_key(client: &reqwest::Client) -> WbiResult<String> {
+    let nav: Nav = helper::get_json(client, "https://api.bilibili.com/x/web-interface/nav").await?;
+    let img = nav.data.wbi_img;
+    Ok(mixin_key(key_of(&img.img_url), key_of(&img.sub_url)))
+}
+
+/// Sign `params`, return the query string with `wts` and `w_rid` appended
+pub(crate) fn sign(params: &[(&str, String)], mixin_key: &str) -> String {
+    let wts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    sign_at(params, mixin_key, wts)
+}
+
+fn sign_at(params: &[(&str, String)], mixin_key: &str, wts: u64) -> String {
+    let mut params: Vec<(&str, String)> = params.to_vec();
+    params.push(("wts", wts.to_string()));
+    params.sort_by(|a, b| a.0.cmp(b.0));
+    let query = params
+        .iter()
+        .map(|(k, v)| {
+            let v: String = v.chars().filter(|c| !"!'()*".contains(*c)).collect();
+            format!("{}={}", urlencoding::encode(k), urlencoding::encode(&v))
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    let w_rid = md5::compute(format!("{query}{mixin_key}"));
+    format!("{query}&w_rid={w_rid:x}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_params() {
+        // the example in bilibili-API-collect
+        let key = mixin_key(
+            key_of("https://i0.hdslb.com/bfs/wbi/7cd084941338484aae1ad9425b84077c.png"),
+            key_of("https://i0.hdslb.com/bfs/wbi/4932caff0ff746eab6f01bf08b70ac45.png"),
+        );
+        assert_eq!(key, "ea1db124af3c7062474693fa704f4ff8");
+        let params = [
+            ("foo", "114".to_owned()),
+            ("bar", "514".to_owned()),
+            ("zab", "1919810".to_owned()),
+        ];
+        assert_eq!(
+            sign_at(&params, &key, 1702204169),
+            "bar=514&foo=114&wts=1702204169&zab=1919810&w_rid=8f6f2b5b3d485fe1886cec6a0be8c5d4"
+        );
+    }
+}
@@ -1,57 +1,217 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
-use core_api::config;
-use core_api::downloader::Downloader;
+use core_api::async_downloader::AsyncDownloader;
+use core_api::config::{self, HttpConfig, QueueConfig};
+use core_api::downloader::Batch;
 use core_api::helper;
+use core_api::manifest::Manifest;
+use core_api::process::Progress;
+use core_api::quality::QualityPolicy;
+use core_api::retry::RetryPolicy;
+use core_api::space::SpaceFilter;
+use core_api::store::TaskRecord;
+use core_api::task::{RangeTiming, TaskOptions};
 use once_cell::sync::OnceCell;
+use tauri::Manager;
 
-static DOWNLOADER: OnceCell<Downloader> = OnceCell::new();
+static DOWNLOADER: OnceCell<AsyncDownloader> = OnceCell::new();
+static APP: OnceCell<tauri::AppHandle> = OnceCell::new();
+
+/// Created on first use on the async runtime of tauri,
+/// the config is applied in `setup` before any command.
+/// Its events are forwarded to the frontend as `task-event`
+fn downloader() -> &'static AsyncDownloader {
+    DOWNLOADER.get_or_init(|| {
+        let dl = AsyncDownloader::new();
+        let mut sub = dl.subscribe();
+        tauri::async_runtime::spawn(async move {
+            while let Some(event) = sub.recv().await {
+                if let Some(app) = APP.get() {
+                    let _ = app.emit_all("task-event", &event);
+                }
+            }
+        });
+        dl
+    })
+}
 
 // Learn more about Tauri commands at https://tauri.app/v1/guides/features/command
+// async, so that listing and parsing don't hold up the main thread
+#[tauri::command]
+async fn add_task(target: String, options: Option<TaskOptions>) -> Result<Batch, String> {
+    let dl = downloader();
+    dl.add_task(target, options.unwrap_or_default())
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn add_space(
+    mid: u64,
+    filter: Option<SpaceFilter>,
+    options: Option<TaskOptions>,
+) -> Result<Batch, String> {
+    let dl = downloader();
+    dl.add_space(
+        mid,
+        &filter.unwrap_or_default(),
+        options.unwrap_or_default(),
+    )
+    .await
+    .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn list_tasks() -> Vec<TaskRecord> {
+    let dl = downloader();
+    dl.list_tasks().await
+}
+
+#[tauri::command]
+async fn history() -> Vec<TaskRecord> {
+    let dl = downloader();
+    dl.history().await
+}
+
+#[tauri::command]
+async fn resumable() -> Vec<Manifest> {
+    let dl = downloader();
+    dl.resumable().await
+}
+
+#[tauri::command]
+async fn resume(id: usize) -> Result<usize, String> {
+    let dl = downloader();
+    dl.resume(id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn discard(id: usize) -> Result<(), String> {
+    let dl = downloader();
+    dl.discard(id).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn children(id: usize) -> Vec<usize> {
+    match DOWNLOADER.get() {
+        Some(dl) => dl.children(id).await,
+        None => Vec::new(),
+    }
+}
+
+#[tauri::command]
+async fn title(id: usize) -> String {
+    match DOWNLOADER.get() {
+        Some(dl) => dl.title(id).await,
+        None => String::new(),
+    }
+}
+
+#[tauri::command]
+async fn quality(id: usize) -> String {
+    match DOWNLOADER.get() {
+        Some(dl) => dl.quality(id).await,
+        None => String::new(),
+    }
+}
+
+#[tauri::command]
+async fn mirrors(id: usize) -> Vec<String> {
+    match DOWNLOADER.get() {
+        Some(dl) => dl.mirrors(id).await,
+        None => Vec::new(),
+    }
+}
+
+#[tauri::command]
+async fn range_timings(id: usize) -> Vec<RangeTiming> {
+    match DOWNLOADER.get() {
+        Some(dl) => dl.range_timings(id).await,
+        None => Vec::new(),
+    }
+}
+
+#[tauri::command]
+async fn process(id: usize) -> String {
+    match DOWNLOADER.get() {
+        Some(dl) => dl.process(id).await,
+        None => String::new(),
+    }
+}
+
+#[tauri::command]
+async fn progress(id: usize) -> Option<Progress> {
+    DOWNLOADER.get()?.progress(id).await
+}
+
+#[tauri::command]
+async fn state(id: usize) -> usize {
+    match DOWNLOADER.get() {
+        Some(dl) => dl.state(id).await,
+        None => 404,
+    }
+    // 0 working; 1 pausing; 2 cancelled; 3 finished; 4 failed; 5 queued
+}
+
+#[tauri::command]
+async fn error(id: usize) -> Option<String> {
+    Some(DOWNLOADER.get()?.error(id).await?.to_string())
+}
+
 #[tauri::command]
-fn add_task(target: String) -> usize {
-    let dl = DOWNLOADER.get_or_init(Downloader::new);
-    dl.add_task(target)
+async fn switch(id: usize) {
+    if let Some(dl) = DOWNLOADER.get() {
+        dl.switch(id).await;
+    }
 }
 
 #[tauri::command]
-fn title(id: usize) -> String {
-    DOWNLOADER.get().map_or_else(String::new, |dl| dl.title(id))
+async fn cancel(id: usize) {
+    if let Some(dl) = DOWNLOADER.get() {
+        dl.cancel(id).await;
+    }
 }
 
 #[tauri::command]
-fn process(id: usize) -> String {
-    DOWNLOADER
-        .get()
-        .map_or_else(String::new, |dl| dl.process(id))
+async fn retry(id: usize) {
+    if let Some(dl) = DOWNLOADER.get() {
+        dl.retry(id).await;
+    }
 }
 
 #[tauri::command]
-fn state(id: usize) -> usize {
-    DOWNLOADER.get().map_or_else(|| 404, |dl| dl.state(id))
-    // 0 working; 1 pausing; 2 cancelled; 3 finished
+async fn set_priority(id: usize, priority: i32) {
+    if let Some(dl) = DOWNLOADER.get() {
+        dl.set_priority(id, priority).await;
+    }
 }
 
 #[tauri::command]
-fn switch(id: usize) {
-    DOWNLOADER
-        .get()
-        .map_or_else(|| {}, |dl: &Downloader| dl.switch(id));
+async fn move_to_top(id: usize) {
+    if let Some(dl) = DOWNLOADER.get() {
+        dl.move_to_top(id).await;
+    }
 }
 
 #[tauri::command]
-fn cancel(id: usize) {
-    DOWNLOADER.get().map_or_else(|| {}, |dl| dl.cancel(id));
+async fn set_rate_limit(rate: Option<u64>, per_task: Option<u64>) {
+    if let Some(dl) = DOWNLOADER.get() {
+        dl.set_rate_limit(rate, per_task).await;
+    }
 }
 
 #[tauri::command]
-fn switch_all() {
-    DOWNLOADER.get().map_or_else(|| {}, |dl| dl.switch_all());
+async fn switch_all() {
+    if let Some(dl) = DOWNLOADER.get() {
+        dl.switch_all().await;
+    }
 }
 
 #[tauri::command]
-fn terminate() {
-    DOWNLOADER.get().map_or_else(|| {}, |dl| dl.terminate());
+async fn terminate() {
+    if let Some(dl) = DOWNLOADER.get() {
+        dl.terminate().await;
+    }
 }
 
 #[tauri::command]
@@ -60,29 +220,122 @@ fn download_dir() -> String {
 }
 
 #[tauri::command]
-fn submit_config(cookie: String, savedir: String, parts: usize, ffmpeg: String) {
-    config::submit_config(cookie, savedir, parts, ffmpeg);
+fn submit_config(
+    cookie: String,
+    savedir: String,
+    parts: usize,
+    ffmpeg: String,
+) -> Result<(), String> {
+    config::submit_config(cookie, savedir, parts, ffmpeg).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn read_config() -> Result<(String, String, usize, String), String> {
+    config::read_config().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn submit_quality(quality: QualityPolicy) -> Result<(), String> {
+    config::submit_quality(quality).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn read_quality() -> Result<QualityPolicy, String> {
+    config::read_quality().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn submit_http(http: HttpConfig) -> Result<(), String> {
+    config::submit_http(http).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn submit_queue(queue: QueueConfig) -> Result<(), String> {
+    config::submit_queue(queue.clone()).map_err(|e| e.to_string())?;
+    if let Some(dl) = DOWNLOADER.get() {
+        dl.set_queue_limits(queue).await;
+    }
+    Ok(())
+}
+
+#[tauri::command]
+fn read_queue() -> Result<QueueConfig, String> {
+    config::read_queue().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn submit_retry(retry: RetryPolicy) -> Result<(), String> {
+    config::submit_retry(retry).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn read_retry() -> Result<RetryPolicy, String> {
+    config::read_retry().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn submit_verify(verify: bool) -> Result<(), String> {
+    config::submit_verify(verify).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn read_verify() -> Result<bool, String> {
+    config::read_verify().map_err(|e| e.to_string())
 }
 
 #[tauri::command]
-fn read_config() -> (String, String, usize, String) {
-    config::read_config()
+fn read_http() -> Result<HttpConfig, String> {
+    config::read_http().map_err(|e| e.to_string())
 }
 
 fn main() {
     tauri::Builder::default()
+        .setup(|app| {
+            // before any command creates the downloader with the defaults
+            if let Err(e) = config::use_config() {
+                println!("Failed to apply the config: {e}");
+            }
+            let _ = APP.set(app.handle());
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             add_task,
+            add_space,
+            list_tasks,
+            history,
+            resumable,
+            resume,
+            discard,
+            children,
             title,
+            quality,
+            mirrors,
+            range_timings,
             process,
+            progress,
             state,
+            error,
             switch,
             cancel,
+            retry,
+            set_priority,
+            move_to_top,
+            set_rate_limit,
             switch_all,
             terminate,
             download_dir,
             submit_config,
             read_config,
+            submit_quality,
+            read_quality,
+            submit_http,
+            read_http,
+            submit_queue,
+            read_queue,
+            submit_retry,
+            read_retry,
+            submit_verify,
+            read_verify,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
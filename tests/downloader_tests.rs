@@ -1,6 +1,9 @@
 #[cfg(test)]
 mod test {
+    use core_api::config;
     use core_api::downloader::Downloader;
+    use core_api::helper;
+    use core_api::task::TaskOptions;
 
     #[test]
     fn size() {
@@ -12,14 +15,27 @@ mod test {
 
     #[test]
     fn run_test() {
+        // away from the journal and the archive of the app
+        std::env::set_var(
+            helper::DATA_DIR_ENV,
+            std::env::temp_dir().join("bili_downloader_tests"),
+        );
+        config::use_config().unwrap();
         let dl = Downloader::new();
         let target = "https://www.bilibili.com/video/BV1Ao4y1b7fj/?".to_owned();
-        let id = dl.add_task(target);
+        let options = TaskOptions {
+            force: true,
+            ..Default::default()
+        };
+        let id = dl.add_task(target, options).unwrap().id;
         loop {
             let process = dl.process(id);
             println!("{}", process);
-            if dl.state(0) == 3 {
-                break;
+            // finished, or cancelled or failed
+            match dl.state(id) {
+                3 => break,
+                2 | 4 => panic!("{:?}", dl.error(id)),
+                _ => std::thread::sleep(std::time::Duration::from_secs(1)),
             }
         }
     }
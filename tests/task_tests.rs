@@ -2,10 +2,24 @@
 mod test {
     use core_api::config;
     use core_api::helper;
-    use core_api::task::Task;
+    use core_api::task::{Task, TaskOptions};
     use std::sync::Arc;
     use tokio::time;
 
+    /// Keep away from the journal and the archive of the app,
+    /// and download even if an earlier run archived the video
+    fn setup() -> TaskOptions {
+        std::env::set_var(
+            helper::DATA_DIR_ENV,
+            std::env::temp_dir().join("bili_task_tests"),
+        );
+        config::use_config().unwrap();
+        TaskOptions {
+            force: true,
+            ..Default::default()
+        }
+    }
+
     #[test]
     fn size() {
         println!("size of task is {} bytes", std::mem::size_of::<Task>());
@@ -13,31 +27,33 @@ mod test {
 
     #[test]
     fn exe_test() {
-        config::use_config();
+        let options = setup();
         let rt = helper::create_rt();
         let tsk = Task::new(
             0,
             "https://www.bilibili.com/video/BV1Ao4y1b7fj/?".to_owned(),
+            options,
         );
         rt.block_on(tsk.execute()).unwrap();
     }
 
     #[test]
     fn special_filename_test() {
-        config::use_config();
+        let options = setup();
         let target = String::from("https://www.bilibili.com/video/BV1ws4y137NX/?");
         let rt = helper::create_rt();
-        let tsk = Task::new(0, target);
+        let tsk = Task::new(0, target, options);
         rt.block_on(tsk.execute()).unwrap();
     }
 
     #[test]
     fn switch_test() {
-        config::use_config();
+        let options = setup();
         let rt = helper::create_rt();
         let task = Arc::new(Task::new(
             0,
             "https://www.bilibili.com/video/BV1Ao4y1b7fj/?".to_owned(),
+            options,
         ));
         rt.block_on(async move {
             let task_c = task.clone();
@@ -54,11 +70,12 @@ mod test {
 
     #[test]
     fn cancel_test() {
-        config::use_config();
+        let options = setup();
         let rt = helper::create_rt();
         let task = Arc::new(Task::new(
             0,
             "https://www.bilibili.com/video/BV1Ao4y1b7fj/?".to_owned(),
+            options,
         ));
         rt.block_on(async move {
             let task_c = task.clone();
@@ -77,11 +94,12 @@ mod test {
 
     #[test]
     fn double_cancel_test() {
-        config::use_config();
+        let options = setup();
         let rt = helper::create_rt();
         let task = Arc::new(Task::new(
             0,
             "https://www.bilibili.com/video/BV1Ao4y1b7fj/?".to_owned(),
+            options,
         ));
         rt.block_on(async move {
             let task_c = task.clone();
@@ -101,11 +119,12 @@ mod test {
 
     #[test]
     fn cancel_after_finished_test() {
-        config::use_config();
+        let options = setup();
         let rt = helper::create_rt();
         let task = Arc::new(Task::new(
             0,
             "https://www.bilibili.com/video/BV1Ao4y1b7fj/?".to_owned(),
+            options,
         ));
         rt.block_on(async move {
             let task_c = task.clone();
@@ -121,11 +140,12 @@ mod test {
 
     #[test]
     fn get_process_test() {
-        config::use_config();
+        let options = setup();
         let rt = helper::create_rt();
         let task = Arc::new(Task::new(
             0,
             "https://www.bilibili.com/video/BV1Ao4y1b7fj/?".to_owned(),
+            options,
         ));
         rt.block_on(async move {
             let task_c = task.clone();